@@ -10,13 +10,28 @@ interface Context {
 
     // get the all contracts that have been called during the transaction
     function get_affected_contracts() external view returns (address[] calldata, bytes[] calldata);
+    // get the full call tree of the transaction in pre-order, kinds: 0 = CALL, 1 = CALLCODE, 2 = DELEGATECALL, 3 = STATICCALL
+    // callers are the contracts executing the call, values are the ETH transferred, depth 0 is the test_call itself
+    function get_call_tree() external view returns (address[] memory callers, address[] memory targets, uint8[] memory kinds, uint256[] memory values, uint256[] memory depths, bool[] memory success, bytes[] memory inputs, bytes[] memory outputs);
     // get the all accounts that have been used in IERC20 calls during the transaction (e.g., caller and receiver of transfer)
+    // derived from calldata (transfer, transferFrom, approve, permit, mint, burn, WETH deposit / withdraw, ERC777 send)
+    // and from emitted Transfer / Approval / Deposit / Withdrawal / Sent events
     function get_affected_accounts_ierc20() external view returns (address[] calldata, address[] calldata);
+    // get the all (owner, spender, token) allowances that may have changed during the transaction
+    function get_affected_allowances_ierc20() external view returns (address[] memory owners, address[] memory spenders, address[] memory tokens);
+    // get the all ERC721 / ERC1155 transfers during the transaction (from calldata and Transfer / TransferSingle / TransferBatch events)
+    // amounts are always 1 for ERC721
+    function get_affected_erc721() external view returns (address[] memory tokens, address[] memory from, address[] memory to, uint256[] memory ids, uint256[] memory amounts);
+    function get_affected_erc1155() external view returns (address[] memory tokens, address[] memory from, address[] memory to, uint256[] memory ids, uint256[] memory amounts);
 
     // does last transaction contain a swap?
     function contains_swap() external view returns (bool);
-    // get the all pairs that have been swapped during the transaction
+    // get the all pairs / pools that have been swapped during the transaction
+    // (Uniswap V2 and Solidly pairs, Uniswap V3 pools, Curve pools and Balancer pools)
     function get_affected_pairs() external view returns (address[] calldata);
+    // get the all swaps during the transaction in order, derived from the Swap / TokenExchange events of the pools
+    // kinds: 0 = Uniswap V2 / Solidly, 1 = Uniswap V3, 2 = Curve, 3 = Balancer
+    function get_affected_swaps() external view returns (address[] memory pools, uint8[] memory kinds, address[] memory tokens_in, address[] memory tokens_out, uint256[] memory amounts_in, uint256[] memory amounts_out);
 
     // call a contract on state before the transaction
     function call_prev_state(address _contract, address caller, bytes memory data, uint256 value) external view returns (bytes memory);
@@ -28,6 +43,10 @@ interface Context {
 
     // set ETH balance of an account
     function set_balance(address account, uint256 amount) external;
+    // get the nonce of an account (fetched from the forked chain if unknown)
+    function get_nonce(address account) external view returns (uint256);
+    // set the nonce of an account, which determines the address of contracts it creates
+    function set_nonce(address account, uint256 nonce) external;
     // buy token with ETH
     function buy_token(address token, uint256 amountETHInWei) external;
 
@@ -35,4 +54,12 @@ interface Context {
     function print_int(string memory key, uint256 i) external;
     function print_address(string memory key, address a) external;
     function print_string(string memory s) external;
+
+    // Assertion functions, failures are recorded and reported by the runner instead of reverting
+    function assert_eq(uint256 a, uint256 b, string memory message) external;
+    function assert_le(uint256 a, uint256 b, string memory message) external;
+    function assert_ge(uint256 a, uint256 b, string memory message) external;
+    // max_delta is relative to b, 1e18 == 100%
+    function assert_approx_eq_rel(uint256 a, uint256 b, uint256 max_delta, string memory message) external;
+    function assert_true(bool condition, string memory message) external;
 }
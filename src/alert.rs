@@ -0,0 +1,237 @@
+// structured alerts for invariants that fire when replaying or monitoring transactions
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use revm::primitives::B160;
+use serde_json::{json, Value};
+use crate::foundry::Reproduction;
+use crate::{rpc, scheme_to_u8, to_str, HistoricalTx, InvariantOutcome, TestHost};
+
+const PAGERDUTY_EVENTS_API: &str = "https://events.pagerduty.com/v2/enqueue";
+
+pub enum Sink {
+    // newline-delimited JSON
+    Stdout,
+    // appended as newline-delimited JSON
+    File(String),
+    // the alert as JSON
+    Webhook(String),
+    // {"text": summary}
+    Slack(String),
+    // {"content": summary}
+    Discord(String),
+    // Events API v2 trigger with the given routing key
+    PagerDuty(String),
+}
+
+impl Sink {
+    // stdout, file:<path>, webhook:<url>, slack:<url>, discord:<url> or pagerduty:<routing key>
+    pub fn parse(sink: &str) -> Self {
+        match sink.split_once(':') {
+            _ if sink == "stdout" => Sink::Stdout,
+            Some(("file", path)) => Sink::File(path.to_string()),
+            Some(("webhook", url)) => Sink::Webhook(url.to_string()),
+            Some(("slack", url)) => Sink::Slack(url.to_string()),
+            Some(("discord", url)) => Sink::Discord(url.to_string()),
+            Some(("pagerduty", key)) => Sink::PagerDuty(key.to_string()),
+            _ => panic!("Unsupported alert sink: {}", sink),
+        }
+    }
+}
+
+pub struct Alert {
+    // name of the spec, i.e. the file name of its build output
+    pub spec: String,
+    pub spec_address: String,
+    pub invariant: String,
+    // replay-tx, blocks, monitor or fuzz
+    pub mode: String,
+    pub chain: String,
+    // 0 for pending transactions
+    pub block: u64,
+    pub transaction: Value,
+    pub call_trace: Value,
+    // print_* output of the invariant
+    pub output: Vec<String>,
+}
+
+impl Alert {
+    // alert for an invariant that fired after `tx`, the call trace is that of the last transaction executed by `host`
+    pub fn new(host: &TestHost, spec: &str, tx: &HistoricalTx, outcome: &InvariantOutcome, mode: &str) -> Self {
+        let hex = |bytes: &[u8]| format!("0x{}", hex::encode(bytes));
+        Alert {
+            spec: spec.to_string(),
+            spec_address: to_str(outcome.spec),
+            invariant: outcome.name.clone(),
+            mode: mode.to_string(),
+            chain: host.chain.clone(),
+            block: tx.block,
+            transaction: json!({
+                "hash": format!("{:?}", tx.hash),
+                "from": to_str(tx.caller),
+                "to": tx.target.map(to_str),
+                "value": tx.value.to_string(),
+                "input": hex(&tx.data),
+            }),
+            call_trace: Value::Array(host.call_tree.iter().map(|call| json!({
+                "depth": call.depth,
+                "kind": scheme_to_u8(call.scheme),
+                "caller": to_str(call.caller),
+                "target": to_str(call.target),
+                "value": call.value.to_string(),
+                "input": hex(&call.input),
+                "success": call.success,
+                "output": hex(&call.output),
+            })).collect()),
+            output: outcome.output.clone(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} of {} fired on {} transaction {}", self.invariant, self.spec, self.chain, self.transaction["hash"].as_str().unwrap())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "spec": self.spec,
+            "spec_address": self.spec_address,
+            "invariant": self.invariant,
+            "mode": self.mode,
+            "chain": self.chain,
+            "block": self.block,
+            "transaction": self.transaction,
+            "call_trace": self.call_trace,
+            "output": self.output,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        })
+    }
+
+    // payload of a webhook sink, None for the other sinks
+    pub fn payload(&self, sink: &Sink) -> Option<(String, Value)> {
+        match sink {
+            Sink::Webhook(url) => Some((url.clone(), self.to_json())),
+            Sink::Slack(url) => Some((url.clone(), json!({"text": self.summary()}))),
+            Sink::Discord(url) => Some((url.clone(), json!({"content": self.summary()}))),
+            Sink::PagerDuty(routing_key) => Some((PAGERDUTY_EVENTS_API.to_string(), json!({
+                "routing_key": routing_key,
+                "event_action": "trigger",
+                // one incident per transaction and invariant
+                "dedup_key": format!("{}:{}:{}", self.transaction["hash"].as_str().unwrap(), self.spec_address, self.invariant),
+                "payload": {
+                    "summary": self.summary(),
+                    "source": self.spec,
+                    "severity": "critical",
+                    "custom_details": self.to_json(),
+                },
+            }))),
+            Sink::Stdout | Sink::File(_) => None,
+        }
+    }
+}
+
+pub struct Alerter {
+    pub sinks: Vec<Sink>,
+    // directory Foundry tests reproducing the alerts are exported to
+    pub foundry: Option<String>,
+    // names of the deployed specs
+    pub spec_names: HashMap<B160, String>,
+}
+
+impl Alerter {
+    pub fn invariant_fired(&self, host: &TestHost, tx: &HistoricalTx, outcome: &InvariantOutcome, mode: &str) {
+        let spec = self.spec_names.get(&outcome.spec).cloned().unwrap_or_else(|| to_str(outcome.spec));
+        if let Some(dir) = &self.foundry {
+            println!("exported {}", Reproduction::new(host, &spec, &outcome.name, &outcome.output).save(dir));
+        }
+        if !self.sinks.is_empty() {
+            self.send(&Alert::new(host, &spec, tx, outcome, mode));
+        }
+    }
+
+    // deliver an alert to all sinks, failing deliveries are reported and do not stop the run
+    pub fn send(&self, alert: &Alert) {
+        for sink in &self.sinks {
+            send(sink, alert);
+        }
+    }
+}
+
+fn send(sink: &Sink, alert: &Alert) {
+    match sink {
+        Sink::Stdout => println!("{}", alert.to_json()),
+        Sink::File(path) => {
+            let written = OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", alert.to_json()));
+            if let Err(e) = written {
+                println!("cannot write alert to {}: {}", path, e);
+            }
+        }
+        _ => {
+            let (url, payload) = alert.payload(sink).unwrap();
+            let sent = rpc::client().post(&url).json(&payload).send().and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                println!("cannot send alert to {}: {}", url, e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sinks() {
+        assert!(matches!(Sink::parse("stdout"), Sink::Stdout));
+        assert!(matches!(Sink::parse("file:/tmp/alerts.jsonl"), Sink::File(path) if path == "/tmp/alerts.jsonl"));
+        assert!(matches!(Sink::parse("slack:https://hooks.slack.com/services/x"), Sink::Slack(url) if url == "https://hooks.slack.com/services/x"));
+        assert!(matches!(Sink::parse("pagerduty:key"), Sink::PagerDuty(key) if key == "key"));
+    }
+
+    fn alert() -> Alert {
+        Alert {
+            spec: "infinite_mint".to_string(),
+            spec_address: "0x0000000000000000000000000000000000001000".to_string(),
+            invariant: "invariant_infinite_mint0".to_string(),
+            mode: "replay-tx".to_string(),
+            chain: "ETH".to_string(),
+            block: 1,
+            transaction: json!({"hash": "0x01", "from": "0x02", "to": "0x03", "value": "0", "input": "0x"}),
+            call_trace: json!([]),
+            output: vec!["total supply changed".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_webhook_payloads() {
+        let alert = alert();
+        let (_, slack) = alert.payload(&Sink::Slack("https://hooks.slack.com/services/x".to_string())).unwrap();
+        assert_eq!(slack["text"], json!("invariant_infinite_mint0 of infinite_mint fired on ETH transaction 0x01"));
+        let (_, discord) = alert.payload(&Sink::Discord("https://discord.com/api/webhooks/x".to_string())).unwrap();
+        assert_eq!(discord["content"], slack["text"]);
+        let (url, pagerduty) = alert.payload(&Sink::PagerDuty("key".to_string())).unwrap();
+        assert_eq!(url, PAGERDUTY_EVENTS_API);
+        assert_eq!(pagerduty["routing_key"], json!("key"));
+        assert_eq!(pagerduty["payload"]["custom_details"]["output"], json!(["total supply changed"]));
+        let (_, webhook) = alert.payload(&Sink::Webhook("http://127.0.0.1/alerts".to_string())).unwrap();
+        assert_eq!(webhook["invariant"], json!("invariant_infinite_mint0"));
+        assert!(alert.payload(&Sink::Stdout).is_none());
+    }
+
+    #[test]
+    fn test_file_sink_appends_lines() {
+        let path = std::env::temp_dir().join(format!("alerts-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let alerter = Alerter { sinks: vec![Sink::File(path.clone())], foundry: None, spec_names: HashMap::new() };
+        alerter.send(&alert());
+        alerter.send(&alert());
+        let lines: Vec<Value> = std::fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["spec"], json!("infinite_mint"));
+    }
+}
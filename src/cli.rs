@@ -1,23 +1,41 @@
+pub mod alert;
+pub mod corpus;
+pub mod foundry;
+pub mod fuzzer;
 pub mod liquidation;
+pub mod minimizer;
+pub mod rpc;
+pub mod fixtures;
+#[cfg(test)]
+pub mod mock_server;
 
 extern crate core;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{Read, Write};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use bytes::Bytes;
-use ethabi::Hash;
 use glob::glob;
-use primitive_types::H160;
-use rand::RngCore;
-use revm::interpreter::{BytecodeLocked, CallInputs, Contract, CreateInputs, Gas, Host, InstructionResult, Interpreter, SelfDestructResult};
-use revm::primitives::{B160, B256, Bytecode, Env, LatestSpec, Spec, U256};
-use revm::primitives::ruint::aliases::{B1, B16};
-use reqwest;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use revm::interpreter::{BytecodeLocked, CallContext, CallInputs, CallScheme, Contract, CreateInputs, CreateScheme, Gas, Host, InstructionResult, Interpreter, SelfDestructResult, Transfer, MAX_CODE_SIZE};
+use revm::precompile::{Precompile, Precompiles};
+use revm::primitives::{
+    B160, B256, Bytecode, Env, SpecId, U256, create_address, create2_address, keccak256,
+    BerlinSpec, ByzantiumSpec, FrontierSpec, HomesteadSpec, IstanbulSpec, LatestSpec, LondonSpec,
+    MergeSpec, PetersburgSpec, ShanghaiSpec, SpuriousDragonSpec, TangerineSpec,
+};
 use revm::interpreter::analysis::to_analysed;
+use revm::primitives::ruint::aliases::U512;
 use serde_json::json;
+use crate::alert::{Alerter, Sink};
+use crate::foundry::{Reproduction, Step};
+use crate::fuzzer::{FuzzTarget, Fuzzer};
 use crate::liquidation::{buy_token, sell_token};
 
 
@@ -25,8 +43,18 @@ pub struct TestHost {
     pub state: HashMap<B160, HashMap<U256, U256>>,
     pub prev_state: HashMap<B160, HashMap<U256, U256>>,
     pub call_traces: Vec<(B160, Bytes)>,
+    pub call_tree: Vec<CallTraceEntry>,
+    pub call_depth: usize,
     pub erc20_affected: HashSet<(B160, B160)>,
+    // (owner, spender, token)
+    pub erc20_allowances_affected: HashSet<(B160, B160, B160)>,
+    // (token, from, to, id, amount)
+    pub erc721_affected: HashSet<(B160, B160, B160, U256, U256)>,
+    pub erc1155_affected: HashSet<(B160, B160, B160, U256, U256)>,
     pub pairs_affected: HashSet<B160>,
+    pub swaps_affected: Vec<SwapInfo>,
+    // cache of tokens of pools, keyed by (pool, selector of the getter, index)
+    pub pool_tokens: HashMap<(B160, [u8; 4], U256), B160>,
     pub env: Env,
     pub logs: HashMap<B160, Vec<(Vec<B256>, Bytes)>>,
     pub codes: HashMap<B160, Bytecode>,
@@ -40,29 +68,503 @@ pub struct TestHost {
     pub target: B160,
     pub inside_contract_call: bool,
     pub chain: String,
+    // hardfork the interpreter runs with, that of the block env unless chosen with --spec
+    pub spec_id: SpecId,
+    pub fixed_spec: bool,
 
     pub set_balance: HashMap<B160, U256>,
+    pub nonces: HashMap<B160, u64>,
+    // prefetch state touched by calls with eth_createAccessList, disabled if the node does not support it
+    pub prefetch: bool,
+    // fail on any state that is not known locally instead of fetching it
+    pub offline: bool,
+
+    // name of the test_* function currently being run
+    pub current_test: String,
+    // failures recorded by the Context assertion helpers during the current test
+    pub assertion_failures: Vec<AssertionFailure>,
+
+    // transaction gas limit of test_call, only enforced in the gas metered build
+    pub gas_limit: u64,
+    // accounts and slots accessed during the current transaction (EIP-2929)
+    pub accessed_accounts: HashSet<B160>,
+    pub accessed_storage: HashSet<(B160, U256)>,
+    // values of written slots at the start of the current transaction (EIP-2200)
+    pub original_storage: HashMap<(B160, U256), U256>,
+    // gas used by each test_call of the current test
+    pub gas_report: Vec<GasReportEntry>,
+    // output of the print_* Context functions
+    pub printed: Vec<String>,
+    // edges executed while collecting coverage, nested calls are inspected only then
+    pub coverage: Option<HashSet<Edge>>,
+    // pc of the JUMP / JUMPI being executed
+    pub jump_from: Option<usize>,
+    // operands of comparisons and values of loaded slots, recorded while collecting coverage
+    pub cmp_log: HashSet<U256>,
+    // state changes to reproduce in a Foundry test, part of the snapshots
+    pub steps: Vec<Step>,
+    // previous values of the changes made by the frames being executed, see `begin_frame` / `end_frame`
+    pub journal: Vec<JournalEntry>,
+    // frames being executed, the journal is dropped when the outermost one ends
+    pub frames: usize,
 }
 
+// account, or slot of it, fetched by a prefetch request and the method fetching it
+type PrefetchedState = (B160, Option<U256>, &'static str);
 
-pub fn get_rpc_url(name: String) -> &'static str {
+// control flow edge: code hash, pc of the jump and pc it jumped to (or fell through to)
+pub type Edge = (B256, usize, usize);
+
+// whether the interpreter accounts gas, see the `no_gas_measuring` feature
+pub const GAS_METERED: bool = !cfg!(feature = "no_gas_measuring");
+// block gas limit of mainnet
+pub const DEFAULT_GAS_LIMIT: u64 = 30_000_000;
+
+
+#[derive(Clone, Debug)]
+pub struct CallTraceEntry {
+    pub depth: usize,
+    pub scheme: CallScheme,
+    // contract executing the call opcode
+    pub caller: B160,
+    // contract being called (code address for DELEGATECALL / CALLCODE)
+    pub target: B160,
+    // ETH actually transferred
+    pub value: U256,
+    pub input: Bytes,
+    pub success: bool,
+    pub output: Bytes,
+}
+
+// address argument at `index` of an ABI encoded call
+fn abi_address_arg(data: &[u8], index: usize) -> Option<B160> {
+    let start = 4 + index * 32;
+    if data.len() < start + 32 {
+        return None;
+    }
+    Some(B160::from_slice(&data[start + 12..start + 32]))
+}
+
+fn topic_to_address(topic: &B256) -> B160 {
+    B160::from_slice(&topic.0[12..32])
+}
+
+// swap of a Swap / TokenExchange event of the supported protocols
+fn swap_from_log(address: B160, topics: &[B256], data: &[u8]) -> Option<SwapInfo> {
+    if topics.is_empty() {
+        return None;
+    }
+    let word = |i: usize| U256::from_be_slice(&data[i * 32..(i + 1) * 32]);
+    let swap = match topics[0].0[0..4] {
+        // Swap(address indexed sender, uint amount0In, uint amount1In, uint amount0Out, uint amount1Out, address indexed to)
+        // Swap(address indexed sender, address indexed to, uint amount0In, uint amount1In, uint amount0Out, uint amount1Out) (Velodrome V2)
+        [0xd7, 0x8a, 0xd9, 0x5f] | [0xb3, 0xe2, 0x77, 0x36] if data.len() >= 128 => {
+            let (amount0_in, amount1_in, amount0_out, amount1_out) = (word(0), word(1), word(2), word(3));
+            let (token_in, token_out, amount_in, amount_out) = if amount0_in > U256::ZERO {
+                (SwapToken::Token0, SwapToken::Token1, amount0_in, amount1_out)
+            } else {
+                (SwapToken::Token1, SwapToken::Token0, amount1_in, amount0_out)
+            };
+            SwapInfo { pool: address, kind: SwapKind::UniswapV2, token_in, token_out, amount_in, amount_out }
+        }
+        // Swap(address indexed sender, address indexed recipient, int256 amount0, int256 amount1, uint160 sqrtPriceX96, uint128 liquidity, int24 tick)
+        [0xc4, 0x20, 0x79, 0xf9] if data.len() >= 64 => {
+            // positive amounts are received by the pool
+            let (amount0, amount1) = (word(0), word(1));
+            let (token_in, token_out, amount_in, amount_out) = if amount0.bit(255) {
+                (SwapToken::Token1, SwapToken::Token0, amount1, amount0.wrapping_neg())
+            } else {
+                (SwapToken::Token0, SwapToken::Token1, amount0, amount1.wrapping_neg())
+            };
+            SwapInfo { pool: address, kind: SwapKind::UniswapV3, token_in, token_out, amount_in, amount_out }
+        }
+        // TokenExchange(address indexed buyer, int128 sold_id, uint256 tokens_sold, int128 bought_id, uint256 tokens_bought)
+        // TokenExchange(address indexed buyer, uint256 sold_id, uint256 tokens_sold, uint256 bought_id, uint256 tokens_bought)
+        // TokenExchangeUnderlying(address indexed buyer, int128 sold_id, uint256 tokens_sold, int128 bought_id, uint256 tokens_bought)
+        [0x8b, 0x3e, 0x96, 0xf2] | [0xb2, 0xe7, 0x6a, 0xe9] | [0xd0, 0x13, 0xca, 0x23] if data.len() >= 128 => {
+            let coin = if topics[0].0[0] == 0xd0 { SwapToken::UnderlyingCoin } else { SwapToken::Coin };
+            SwapInfo { pool: address, kind: SwapKind::Curve, token_in: coin(word(0)), token_out: coin(word(2)), amount_in: word(1), amount_out: word(3) }
+        }
+        // Swap(bytes32 indexed poolId, address indexed tokenIn, address indexed tokenOut, uint256 amountIn, uint256 amountOut)
+        [0x21, 0x70, 0xc7, 0x41] if topics.len() == 4 && data.len() >= 64 => {
+            // pool address is the first 20 bytes of the pool id
+            SwapInfo {
+                pool: B160::from_slice(&topics[1].0[0..20]),
+                kind: SwapKind::Balancer,
+                token_in: SwapToken::Address(topic_to_address(&topics[2])),
+                token_out: SwapToken::Address(topic_to_address(&topics[3])),
+                amount_in: word(0),
+                amount_out: word(1),
+            }
+        }
+        _ => return None,
+    };
+    Some(swap)
+}
+
+fn ethabi_uint_to_u256(x: ethabi::Uint) -> U256 {
+    let mut bytes = [0u8; 32];
+    x.to_big_endian(&mut bytes);
+    U256::from_be_bytes(bytes)
+}
+
+fn encode_nft_transfers(transfers: &HashSet<(B160, B160, B160, U256, U256)>) -> Vec<u8> {
+    ethabi::encode(&[
+        ethabi::Token::Array(transfers.iter().map(|(token, _, _, _, _)| ethabi::Token::Address(
+            ethabi::Address::from_slice(&token.0)
+        )).collect()),
+        ethabi::Token::Array(transfers.iter().map(|(_, from, _, _, _)| ethabi::Token::Address(
+            ethabi::Address::from_slice(&from.0)
+        )).collect()),
+        ethabi::Token::Array(transfers.iter().map(|(_, _, to, _, _)| ethabi::Token::Address(
+            ethabi::Address::from_slice(&to.0)
+        )).collect()),
+        ethabi::Token::Array(transfers.iter().map(|(_, _, _, id, _)| ethabi::Token::Uint(
+            ethabi::Uint::from_big_endian(&id.to_be_bytes::<32>())
+        )).collect()),
+        ethabi::Token::Array(transfers.iter().map(|(_, _, _, _, amount)| ethabi::Token::Uint(
+            ethabi::Uint::from_big_endian(&amount.to_be_bytes::<32>())
+        )).collect()),
+    ])
+}
+
+pub fn scheme_to_u8(scheme: CallScheme) -> u8 {
+    match scheme {
+        CallScheme::Call => 0,
+        CallScheme::CallCode => 1,
+        CallScheme::DelegateCall => 2,
+        CallScheme::StaticCall => 3,
+    }
+}
+
+fn is_call_success(ret: InstructionResult) -> bool {
+    matches!(ret, InstructionResult::Continue | InstructionResult::Stop
+        | InstructionResult::Return | InstructionResult::SelfDestruct)
+}
+
+// run an interpreter (`run` or `run_inspect`) with the revm Spec type of a SpecId
+macro_rules! run_with_spec {
+    ($spec_id: expr, $interpreter: expr, $run: ident, $host: expr) => {
+        match $spec_id {
+            SpecId::FRONTIER | SpecId::FRONTIER_THAWING => $interpreter.$run::<TestHost, FrontierSpec>($host),
+            SpecId::HOMESTEAD | SpecId::DAO_FORK => $interpreter.$run::<TestHost, HomesteadSpec>($host),
+            SpecId::TANGERINE => $interpreter.$run::<TestHost, TangerineSpec>($host),
+            SpecId::SPURIOUS_DRAGON => $interpreter.$run::<TestHost, SpuriousDragonSpec>($host),
+            SpecId::BYZANTIUM => $interpreter.$run::<TestHost, ByzantiumSpec>($host),
+            SpecId::CONSTANTINOPLE | SpecId::PETERSBURG => $interpreter.$run::<TestHost, PetersburgSpec>($host),
+            SpecId::ISTANBUL | SpecId::MUIR_GLACIER => $interpreter.$run::<TestHost, IstanbulSpec>($host),
+            SpecId::BERLIN => $interpreter.$run::<TestHost, BerlinSpec>($host),
+            SpecId::LONDON | SpecId::ARROW_GLACIER | SpecId::GRAY_GLACIER => $interpreter.$run::<TestHost, LondonSpec>($host),
+            SpecId::MERGE => $interpreter.$run::<TestHost, MergeSpec>($host),
+            SpecId::SHANGHAI => $interpreter.$run::<TestHost, ShanghaiSpec>($host),
+            SpecId::CANCUN | SpecId::LATEST => $interpreter.$run::<TestHost, LatestSpec>($host),
+        }
+    };
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapKind {
+    // Uniswap V2 and Solidly forks sharing its pair interface
+    UniswapV2,
+    UniswapV3,
+    Curve,
+    Balancer,
+}
+
+// token of a swap, named by the event or read from the pool when a spec asks for the swaps,
+// so that no call is made while the transaction runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapToken {
+    Address(B160),
+    // token0() / token1() of a pair or pool
+    Token0,
+    Token1,
+    // coins(i) / underlying_coins(i) of a Curve pool
+    Coin(U256),
+    UnderlyingCoin(U256),
+}
+
+#[derive(Clone, Debug)]
+pub struct SwapInfo {
+    pub pool: B160,
+    pub kind: SwapKind,
+    pub token_in: SwapToken,
+    pub token_out: SwapToken,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+
+pub struct AssertionFailure {
+    pub assertion: String,
+    pub expected: String,
+    pub actual: String,
+    pub message: String,
+    pub test: String,
+    // contract that called the assertion helper
+    pub contract: B160,
+    // number of calls traced during the last test_call when the assertion failed
+    pub trace_index: usize,
+}
+
+impl fmt::Display for AssertionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[FAIL] {} in {} @ {:?} (after {} traced calls): {}",
+                 self.assertion, self.test, self.contract, self.trace_index, self.message)?;
+        writeln!(f, "    expected: {}", self.expected)?;
+        write!(f, "      actual: {}", self.actual)
+    }
+}
+
+
+pub struct GasReportEntry {
+    pub target: B160,
+    pub input: Bytes,
+    pub result: InstructionResult,
+    // gas used by the transaction including intrinsic gas, after refunds
+    pub gas_used: u64,
+    pub refunded: u64,
+}
+
+impl fmt::Display for GasReportEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let selector = if self.input.len() >= 4 { hex::encode(&self.input[0..4]) } else { "fallback".to_string() };
+        write!(f, "    test_call {:?} {}: {} gas (refunded {}), {:?}",
+               self.target, selector, self.gas_used, self.refunded, self.result)
+    }
+}
+
+// result of an invariant_* function of a spec after a transaction
+#[derive(Clone)]
+pub struct InvariantOutcome {
+    pub spec: B160,
+    pub name: String,
+    pub result: InstructionResult,
+    // the invariant returned true, i.e. it is violated
+    pub fired: bool,
+    // print_* output of the invariant
+    pub output: Vec<String>,
+}
+
+impl fmt::Display for InvariantOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !is_call_success(self.result) {
+            write!(f, "    {} @ {:?}: failed ({:?})", self.name, self.spec, self.result)
+        } else if self.fired {
+            write!(f, "    {} @ {:?}: FIRED", self.name, self.spec)
+        } else {
+            write!(f, "    {} @ {:?}: ok", self.name, self.spec)
+        }
+    }
+}
+
+// value of the state before a change made by a call frame, undone when the frame fails
+pub enum JournalEntry {
+    Balance(B160, Option<U256>),
+    Nonce(B160, Option<u64>),
+    Storage(B160, U256, U256),
+    Code(B160, Option<Bytecode>),
+}
+
+// local state of the accounts, to undo a simulated transaction
+#[derive(Clone)]
+pub struct StateSnapshot {
+    state: HashMap<B160, HashMap<U256, U256>>,
+    set_balance: HashMap<B160, U256>,
+    nonces: HashMap<B160, u64>,
+    codes: HashMap<B160, Bytecode>,
+    steps: Vec<Step>,
+}
+
+// transaction fetched from the chain
+pub struct HistoricalTx {
+    pub hash: B256,
+    // 0 for pending transactions
+    pub block: u64,
+    pub caller: B160,
+    // None for contract creations
+    pub target: Option<B160>,
+    pub data: Bytes,
+    pub value: U256,
+    pub gas_limit: u64,
+    pub gas_price: U256,
+}
+
+impl HistoricalTx {
+    // from a transaction object of eth_getTransactionByHash / eth_getBlockByNumber
+    pub fn from_json(tx: &serde_json::Value) -> Self {
+        let address = |value: &serde_json::Value| B160::from_str(value.as_str().unwrap().trim_start_matches("0x")).unwrap();
+        HistoricalTx {
+            hash: B256::from_str(tx["hash"].as_str().unwrap().trim_start_matches("0x")).unwrap(),
+            block: if tx["blockNumber"].is_null() { 0 } else { hex_to_u64(&tx["blockNumber"]) },
+            caller: address(&tx["from"]),
+            target: (!tx["to"].is_null()).then(|| address(&tx["to"])),
+            data: Bytes::from(hex::decode(tx["input"].as_str().unwrap().trim_start_matches("0x")).unwrap()),
+            value: hex_to_u256(&tx["value"]),
+            gas_limit: hex_to_u64(&tx["gas"]),
+            gas_price: tx.get("gasPrice").map_or(U256::ZERO, hex_to_u256),
+        }
+    }
+}
+
+fn get_transaction(hash: &str) -> HistoricalTx {
+    let tx = rpc::request("eth_getTransactionByHash", json!([hash])).unwrap_or_else(|error| panic!("{}", error));
+    if tx.is_null() {
+        panic!("transaction {} not found", hash);
+    }
+    if tx["blockNumber"].is_null() {
+        panic!("transaction {} is pending", hash);
+    }
+    HistoricalTx::from_json(&tx)
+}
+
+// with full transaction objects if `transactions` is set
+fn get_block(number: u64, transactions: bool) -> serde_json::Value {
+    let block = rpc::request("eth_getBlockByNumber", json!([format!("{:#x}", number), transactions])).unwrap_or_else(|error| panic!("{}", error));
+    if block.is_null() {
+        panic!("block {} not found", number);
+    }
+    block
+}
+
+// blocks per eth_getLogs request, providers reject larger ranges
+const LOGS_RANGE: u64 = 1000;
+
+// hashes of the transactions in which one of the contracts emitted a log
+fn get_logged_transactions(from: u64, to: u64, contracts: &HashSet<B160>) -> HashSet<B256> {
+    let addresses: Vec<String> = contracts.iter().map(|address| to_str(*address)).collect();
+    let mut hashes = HashSet::new();
+    for start in (from..=to).step_by(LOGS_RANGE as usize) {
+        let logs = rpc::request("eth_getLogs", json!([{
+            "fromBlock": format!("{:#x}", start),
+            "toBlock": format!("{:#x}", (start + LOGS_RANGE - 1).min(to)),
+            "address": addresses,
+        }])).unwrap_or_else(|error| panic!("{}", error));
+        for log in logs.as_array().unwrap() {
+            hashes.insert(B256::from_str(log["transactionHash"].as_str().unwrap().trim_start_matches("0x")).unwrap());
+        }
+    }
+    hashes
+}
+
+// intrinsic gas of a transaction calling a contract without access list
+pub fn intrinsic_gas(data: &[u8]) -> u64 {
+    let zero_bytes = data.iter().filter(|b| **b == 0).count() as u64;
+    21000 + zero_bytes * 4 + (data.len() as u64 - zero_bytes) * 16
+}
+
+
+// public endpoints of a chain, in order of preference
+pub fn get_rpc_urls(name: String) -> Vec<&'static str> {
     match name.as_str() {
-        "ETH" => "https://eth.llamarpc.com",
-        "BSC" => "https://bsc-dataseed.binance.org/",
-        "BSC_TESTNET" => "https://data-seed-prebsc-1-s1.binance.org:8545/",
-        "POLYGON" => "https://rpc-mainnet.maticvigil.com/",
-        "MUMBAI" => "https://rpc-mumbai.maticvigil.com/",
-        "ARBITRUM" => "https://arb1.arbitrum.io/rpc",
+        "ETH" => vec!["https://eth.llamarpc.com", "https://rpc.ankr.com/eth", "https://ethereum.publicnode.com"],
+        "BSC" => vec!["https://bsc-dataseed.binance.org/", "https://bsc-dataseed1.defibit.io/", "https://bsc.publicnode.com"],
+        "BSC_TESTNET" => vec!["https://data-seed-prebsc-1-s1.binance.org:8545/", "https://data-seed-prebsc-2-s1.binance.org:8545/"],
+        "POLYGON" => vec!["https://rpc-mainnet.maticvigil.com/", "https://polygon-rpc.com", "https://polygon-bor.publicnode.com"],
+        "MUMBAI" => vec!["https://rpc-mumbai.maticvigil.com/", "https://polygon-mumbai-bor.publicnode.com"],
+        "ARBITRUM" => vec!["https://arb1.arbitrum.io/rpc", "https://arbitrum-one.publicnode.com"],
         _ => {
             panic!("Invalid chain type");
         }
     }
 }
 
-pub static mut RPC_URL: &str = "";
+// Etherscan compatible block explorer API of the chain
+pub fn get_explorer_api(name: &str) -> &'static str {
+    match name {
+        "ETH" => "https://api.etherscan.io/api",
+        "BSC" => "https://api.bscscan.com/api",
+        "BSC_TESTNET" => "https://api-testnet.bscscan.com/api",
+        "POLYGON" => "https://api.polygonscan.com/api",
+        "MUMBAI" => "https://api-testnet.polygonscan.com/api",
+        "ARBITRUM" => "https://api.arbiscan.io/api",
+        _ => {
+            panic!("Invalid chain type");
+        }
+    }
+}
+
+// block number or timestamp from which a hardfork is active
+enum Activation {
+    Block(u64),
+    Time(u64),
+}
+
+// hardforks of a chain that change the EVM, newest first
+fn hardforks(name: &str) -> &'static [(Activation, SpecId)] {
+    use Activation::{Block, Time};
+    match name {
+        "ETH" => &[
+            (Block(17034870), SpecId::SHANGHAI),
+            (Block(15537394), SpecId::MERGE),
+            (Block(12965000), SpecId::LONDON),
+            (Block(12244000), SpecId::BERLIN),
+            (Block(9069000), SpecId::ISTANBUL),
+            (Block(7280000), SpecId::PETERSBURG),
+            (Block(4370000), SpecId::BYZANTIUM),
+            (Block(2675000), SpecId::SPURIOUS_DRAGON),
+            (Block(2463000), SpecId::TANGERINE),
+            (Block(1150000), SpecId::HOMESTEAD),
+            (Block(0), SpecId::FRONTIER),
+        ],
+        // Berlin and London came with Hertz, PUSH0 with Shanghai / Kepler
+        "BSC" => &[
+            (Time(1705996800), SpecId::SHANGHAI),
+            (Block(31302048), SpecId::LONDON),
+            (Block(0), SpecId::ISTANBUL),
+        ],
+        "POLYGON" => &[
+            (Block(50523000), SpecId::SHANGHAI),
+            (Block(23850000), SpecId::LONDON),
+            (Block(14750000), SpecId::BERLIN),
+            (Block(3395000), SpecId::ISTANBUL),
+            (Block(0), SpecId::PETERSBURG),
+        ],
+        // the past hardforks of the testnets and of Arbitrum (whose EVM follows the ArbOS version) are not tracked
+        "BSC_TESTNET" | "MUMBAI" | "ARBITRUM" => &[(Block(0), SpecId::SHANGHAI)],
+        _ => {
+            panic!("Invalid chain type");
+        }
+    }
+}
+
+// hardfork of the chain at a block (number, timestamp) or at its head, capped to Shanghai: the CANCUN spec
+// of the pinned revm does not implement the Cancun opcodes (TSTORE, TLOAD, MCOPY, BLOBHASH) yet
+pub fn get_spec_id(name: &str, block: Option<(u64, u64)>) -> SpecId {
+    let hardforks = hardforks(name);
+    let Some((number, timestamp)) = block else {
+        return hardforks[0].1;
+    };
+    hardforks.iter()
+        .find(|(activation, _)| match activation {
+            Activation::Block(activation) => number >= *activation,
+            Activation::Time(activation) => timestamp >= *activation,
+        })
+        .map(|(_, spec_id)| *spec_id)
+        .unwrap()
+}
 
+// hardfork by its revm name, e.g. London or Shanghai
+pub fn parse_spec_id(name: &str) -> SpecId {
+    let spec_id = SpecId::from(name);
+    if spec_id == SpecId::LATEST && name != "Latest" {
+        panic!("Unsupported spec {}", name);
+    }
+    spec_id
+}
 
 
+// |a - b| / b <= max_delta / 1e18, computed in 512 bits so that large operands do not overflow,
+// only an exact match passes against zero
+fn approx_eq_rel(a: U256, b: U256, max_delta: U256) -> bool {
+    if b == U256::ZERO {
+        return a == b;
+    }
+    let delta = if a > b { a - b } else { b - a };
+    let scaled: U512 = delta.widening_mul(U256::from(1_000_000_000_000_000_000u64));
+    scaled / U512::from(b) <= U512::from(max_delta)
+}
 
 fn hex_encode_with_prefix(bytes: &[u8]) -> String {
     let mut hex = hex::encode(bytes);
@@ -74,116 +576,720 @@ pub fn to_str(address: B160) -> String {
     hex_encode_with_prefix(address.0.as_slice())
 }
 
+fn hex_to_u256(value: &serde_json::Value) -> U256 {
+    U256::from_str_radix(value.as_str().unwrap().trim_start_matches("0x"), 16).unwrap()
+}
+
+// `alloc` quantities are hex or decimal strings, or numbers
+fn alloc_to_u256(value: &serde_json::Value) -> U256 {
+    match value {
+        serde_json::Value::Number(number) => U256::from(number.as_u64().unwrap()),
+        serde_json::Value::String(string) => match string.strip_prefix("0x") {
+            Some(hex) => U256::from_str_radix(hex, 16).unwrap(),
+            None => U256::from_str_radix(string, 10).unwrap(),
+        },
+        _ => panic!("invalid quantity {}", value),
+    }
+}
+
+fn hex_to_u64(value: &serde_json::Value) -> u64 {
+    u64::from_str_radix(value.as_str().unwrap().trim_start_matches("0x"), 16).unwrap()
+}
+
+fn hex_to_code(value: &serde_json::Value) -> Bytecode {
+    let code = value.as_str().unwrap().trim_start_matches("0x");
+    to_analysed(Bytecode::new_raw(Bytes::from(hex::decode(code).unwrap())))
+}
+
+fn slot_to_str(slot: U256) -> String {
+    hex_encode_with_prefix(&slot.to_be_bytes::<32>())
+}
+
+// forked state the EVM cannot continue without, a failed request aborts the test or transaction being run
+fn fetch(method: &str, params: serde_json::Value) -> serde_json::Value {
+    rpc::request(method, params).unwrap_or_else(|error| panic!("{}", error))
+}
+
 fn get_balance_rpc(address: B160) -> U256 {
-    // call eth_getBalance
-    let client = reqwest::blocking::Client::new();
-    let url = unsafe { RPC_URL.to_string() };
-    let response = client.post(&url)
-        .json(&json!({
-            "jsonrpc": "2.0",
-            "method": "eth_getBalance",
-            "params": [to_str(address), "latest"],
-            "id": 1
-        }))
-        .send()
-        .unwrap();
-    let body = response.text().unwrap();
-    let j = serde_json::from_str::<serde_json::Value>(&body).unwrap();
-    let mut balance = j["result"].as_str().unwrap();
-    balance = balance.trim_start_matches("0x");
-    // println!("get_balance_rpc: {}", balance);
-    U256::from_str_radix(balance, 16).unwrap()
+    hex_to_u256(&fetch("eth_getBalance", json!([to_str(address), rpc::fork_block()])))
+}
+
+fn get_nonce_rpc(address: B160) -> u64 {
+    hex_to_u64(&fetch("eth_getTransactionCount", json!([to_str(address), rpc::fork_block()])))
 }
 
 fn get_block_hash(block: U256) -> B256 {
-    let mut block_hex: String = hex::encode::<[u8; 32]>(block.to_be_bytes());
-    block_hex.insert_str(0, "0x");
-    // call eth_getBlockByNumber
-    let client = reqwest::blocking::Client::new();
-    let url = unsafe { RPC_URL.to_string() };
-    let response = client.post(&url)
-        .json(&json!({
-            "jsonrpc": "2.0",
-            "method": "eth_getBlockByNumber",
-            "params": [block_hex, false],
-            "id": 1
-        }))
-        .send()
-        .unwrap();
-    let body = response.text().unwrap();
-    let j = serde_json::from_str::<serde_json::Value>(&body).unwrap();
-    let hash = j["result"]["hash"].as_str().unwrap();
-    // println!("get_block_hash: {}", hash);
-    B256::from_slice(hex::decode(hash).unwrap().as_slice())
+    let block = fetch("eth_getBlockByNumber", json!([slot_to_str(block), false]));
+    let hash = block["hash"].as_str().unwrap();
+    B256::from_slice(hex::decode(hash.trim_start_matches("0x")).unwrap().as_slice())
 }
 
 fn get_code_rpc(address: B160) -> Bytecode {
-    // call eth_getCode
-    let client = reqwest::blocking::Client::new();
-    let url = unsafe { RPC_URL.to_string() };
-    let response = client.post(&url)
-        .json(&json!({
-            "jsonrpc": "2.0",
-            "method": "eth_getCode",
-            "params": [to_str(address), "latest"],
-            "id": 1
-        }))
-        .send()
-        .unwrap();
-    let body = response.text().unwrap();
-    let j = serde_json::from_str::<serde_json::Value>(&body).unwrap();
-    let code = j["result"].as_str().unwrap();
-    let code = code.trim_start_matches("0x");
-    // println!("get_code_rpc: {} {}", address, code);
-    to_analysed(Bytecode::new_raw(Bytes::from(hex::decode(code).unwrap())))
+    hex_to_code(&fetch("eth_getCode", json!([to_str(address), rpc::fork_block()])))
 }
 
 fn get_storage_slot(address: B160, slot: U256) -> U256 {
-    // call eth_getStorageAt
-    let mut slot_hex: String = hex::encode::<[u8; 32]>(slot.to_be_bytes());
-    // add prefix to slot_hex
-    slot_hex.insert_str(0, "0x");
-    let client = reqwest::blocking::Client::new();
-    let url = unsafe { RPC_URL.to_string() };
-    let response = client.post(&url)
-        .json(&json!({
-            "jsonrpc": "2.0",
-            "method": "eth_getStorageAt",
-            "params": [to_str(address), slot_hex, "latest"],
-            "id": 1
-        }))
-        .send()
-        .unwrap();
-    let body = response.text().unwrap();
-    let j = serde_json::from_str::<serde_json::Value>(&body).unwrap();
-    let slot_val = j["result"].as_str().unwrap();
-    let slot_val = &slot_val[2..];
-    // println!("get_storage_slot: {}@{}", slot_val, slot);
-    U256::from_str_radix(slot_val, 16).unwrap()
+    hex_to_u256(&fetch("eth_getStorageAt", json!([to_str(address), slot_to_str(slot), rpc::fork_block()])))
 }
 
 impl TestHost {
+    pub fn new(context_mapping: HashMap<[u8; 4], String>, context_abi: ethabi::Contract, chain: String) -> Self {
+        TestHost {
+            state: Default::default(),
+            prev_state: Default::default(),
+            call_traces: vec![],
+            call_tree: vec![],
+            call_depth: 0,
+            erc20_affected: Default::default(),
+            erc20_allowances_affected: Default::default(),
+            erc721_affected: Default::default(),
+            erc1155_affected: Default::default(),
+            pairs_affected: Default::default(),
+            swaps_affected: vec![],
+            pool_tokens: Default::default(),
+            env: Default::default(),
+            logs: Default::default(),
+            codes: Default::default(),
+            abis: Default::default(),
+            context_mapping,
+            context_abi,
+            origin: Default::default(),
+            caller: Default::default(),
+            value: Default::default(),
+            data: Default::default(),
+            target: Default::default(),
+            inside_contract_call: false,
+            spec_id: get_spec_id(&chain, None),
+            fixed_spec: false,
+            chain,
+            set_balance: Default::default(),
+            nonces: Default::default(),
+            prefetch: true,
+            offline: false,
+            current_test: Default::default(),
+            assertion_failures: vec![],
+            gas_limit: DEFAULT_GAS_LIMIT,
+            accessed_accounts: Default::default(),
+            accessed_storage: Default::default(),
+            original_storage: Default::default(),
+            gas_report: vec![],
+            printed: vec![],
+            coverage: None,
+            jump_from: None,
+            cmp_log: HashSet::new(),
+            steps: vec![],
+            journal: vec![],
+            frames: 0,
+        }
+    }
+
     pub fn add_balance(&mut self, addr: &B160, balance: U256) {
         let (initial_balance, _) = self.balance(*addr).unwrap();
+        self.journal.push(JournalEntry::Balance(*addr, Some(initial_balance)));
         self.set_balance.insert(*addr, initial_balance + balance);
     }
 
+    // panics if the account cannot pay, callers check the balance first
     pub fn reduce_balance(&mut self, addr: &B160, balance: U256) {
         let (initial_balance, _) = self.balance(*addr).unwrap();
-        self.set_balance.insert(*addr, initial_balance - balance);
+        let reduced = initial_balance.checked_sub(balance)
+            .unwrap_or_else(|| panic!("balance of {:?} is {}, cannot reduce it by {}", addr, initial_balance, balance));
+        self.journal.push(JournalEntry::Balance(*addr, Some(initial_balance)));
+        self.set_balance.insert(*addr, reduced);
+    }
+
+    // the previous balance is not fetched, an account whose balance was not known is forgotten again if the frame fails
+    fn set_balance_journaled(&mut self, address: B160, balance: U256) {
+        self.journal.push(JournalEntry::Balance(address, self.set_balance.get(&address).copied()));
+        self.set_balance.insert(address, balance);
+    }
+
+    fn set_nonce_journaled(&mut self, address: B160, nonce: u64) {
+        self.journal.push(JournalEntry::Nonce(address, self.nonces.get(&address).copied()));
+        self.nonces.insert(address, nonce);
+    }
+
+    // start a frame whose changes are undone if it fails, returns the checkpoint to pass to `end_frame`
+    pub fn begin_frame(&mut self) -> usize {
+        self.frames += 1;
+        self.journal.len()
+    }
+
+    // undo the changes made since the checkpoint in reverse order if the frame failed,
+    // the changes of a successful frame are undone with the frame that started it
+    pub fn end_frame(&mut self, checkpoint: usize, success: bool) {
+        self.frames -= 1;
+        if !success {
+            self.revert_to(checkpoint);
+        }
+        if self.frames == 0 {
+            self.journal.clear();
+        }
+    }
+
+    // undo everything the transaction being run changed after it was aborted half way, e.g. by a failed request
+    pub fn abort_transaction(&mut self) {
+        self.revert_to(0);
+        self.frames = 0;
+        self.call_depth = 0;
+        self.inside_contract_call = false;
+    }
+
+    fn revert_to(&mut self, checkpoint: usize) {
+        while self.journal.len() > checkpoint {
+            match self.journal.pop().unwrap() {
+                JournalEntry::Balance(address, Some(balance)) => { self.set_balance.insert(address, balance); }
+                JournalEntry::Balance(address, None) => { self.set_balance.remove(&address); }
+                JournalEntry::Nonce(address, Some(nonce)) => { self.nonces.insert(address, nonce); }
+                JournalEntry::Nonce(address, None) => { self.nonces.remove(&address); }
+                JournalEntry::Storage(address, slot, value) => { self.state.entry(address).or_default().insert(slot, value); }
+                JournalEntry::Code(address, Some(code)) => { self.codes.insert(address, code); }
+                JournalEntry::Code(address, None) => { self.codes.remove(&address); }
+            }
+        }
+    }
+
+    // move ETH between accounts, returns false without moving anything if the sender cannot pay
+    pub fn transfer(&mut self, from: &B160, to: &B160, value: U256) -> bool {
+        let (balance, _) = self.balance(*from).unwrap();
+        if balance < value {
+            return false;
+        }
+        self.reduce_balance(from, value);
+        self.add_balance(to, value);
+        true
+    }
+
+    // output of the print_* Context functions
+    fn print(&mut self, line: String) {
+        println!("{}", line);
+        self.printed.push(line);
+    }
+
+    fn record_call(&mut self, caller: B160, target: B160, data: Bytes, value: U256, success: bool, label: Option<String>) {
+        let origin = self.env.tx.caller;
+        self.steps.push(Step::Call { origin, caller, target, data, value, success, label });
+    }
+
+    // run the interpreter of a nested call, inspected with step / step_end while collecting coverage
+    fn run(&mut self, interpreter: &mut Interpreter) -> InstructionResult {
+        if self.coverage.is_some() {
+            run_with_spec!(self.spec_id, interpreter, run_inspect, self)
+        } else {
+            run_with_spec!(self.spec_id, interpreter, run, self)
+        }
+    }
+
+    // state missing locally is fetched from RPC, in offline mode it is an error
+    fn require_online(&self, missing: impl FnOnce() -> String) {
+        if self.offline {
+            panic!("offline mode: {} is not in the local state", missing());
+        }
+    }
+
+    pub fn nonce(&mut self, address: B160) -> u64 {
+        match self.nonces.get(&address) {
+            Some(nonce) => *nonce,
+            None => {
+                self.require_online(|| format!("nonce of {:?}", address));
+                let nonce = get_nonce_rpc(address);
+                self.nonces.insert(address, nonce);
+                nonce
+            }
+        }
+    }
+
+    // speculatively fetch the state a call touches on chain in batches,
+    // instead of fetching it slot by slot while executing
+    pub fn prefetch(&mut self, caller: B160, target: B160, data: &Bytes, value: U256) {
+        if !self.prefetch || self.offline {
+            return;
+        }
+        let access_list = match rpc::try_request("eth_createAccessList", json!([{
+            "from": to_str(caller),
+            "to": to_str(target),
+            "data": hex_encode_with_prefix(data),
+            "value": format!("{:#x}", value),
+        }, rpc::fork_block()])) {
+            Ok(result) => result["accessList"].clone(),
+            Err(rpc::RpcError::Unsupported) => {
+                self.prefetch = false;
+                return;
+            }
+            // e.g. the call fails on chain
+            Err(_) => return,
+        };
+        let (requests, fetched) = self.prefetch_requests(caller, target, &access_list);
+
+        // state that is already known locally may have been changed and is kept
+        for ((address, slot, method), result) in fetched.into_iter().zip(rpc::batch_request(&requests)) {
+            let Ok(result) = result else { continue };
+            match method {
+                "eth_getCode" => { self.codes.entry(address).or_insert_with(|| hex_to_code(&result)); }
+                "eth_getBalance" => { self.set_balance.entry(address).or_insert_with(|| hex_to_u256(&result)); }
+                "eth_getTransactionCount" => { self.nonces.entry(address).or_insert_with(|| hex_to_u64(&result)); }
+                _ => {
+                    self.state.entry(address).or_default().entry(slot.unwrap()).or_insert_with(|| hex_to_u256(&result));
+                }
+            }
+        }
+    }
+
+    // requests for the state of the access list and of the caller and target that is not known locally,
+    // with the account / slot each one fetches
+    fn prefetch_requests(&self, caller: B160, target: B160, access_list: &serde_json::Value)
+        -> (Vec<(&'static str, serde_json::Value)>, Vec<PrefetchedState>) {
+        let mut accounts = vec![caller, target];
+        let mut slots = vec![];
+        for item in access_list.as_array().into_iter().flatten() {
+            let address = B160::from_str(item["address"].as_str().unwrap().trim_start_matches("0x")).unwrap();
+            accounts.push(address);
+            for key in item["storageKeys"].as_array().into_iter().flatten() {
+                slots.push((address, hex_to_u256(key)));
+            }
+        }
+
+        let mut requests = vec![];
+        let mut fetched = vec![];
+        for address in accounts {
+            if !self.codes.contains_key(&address) {
+                requests.push(("eth_getCode", json!([to_str(address), rpc::fork_block()])));
+                fetched.push((address, None, "eth_getCode"));
+            }
+            if !self.set_balance.contains_key(&address) {
+                requests.push(("eth_getBalance", json!([to_str(address), rpc::fork_block()])));
+                fetched.push((address, None, "eth_getBalance"));
+            }
+            if !self.nonces.contains_key(&address) {
+                requests.push(("eth_getTransactionCount", json!([to_str(address), rpc::fork_block()])));
+                fetched.push((address, None, "eth_getTransactionCount"));
+            }
+        }
+        for (address, slot) in slots {
+            if !self.state.get(&address).is_some_and(|storage| storage.contains_key(&slot)) {
+                requests.push(("eth_getStorageAt", json!([to_str(address), slot_to_str(slot), rpc::fork_block()])));
+                fetched.push((address, Some(slot), "eth_getStorageAt"));
+            }
+        }
+        (requests, fetched)
+    }
+
+    // local state in the geth / anvil genesis `alloc` format
+    // {"0x<address>": {"balance": "0x..", "nonce": "0x..", "code": "0x..", "storage": {"0x<slot>": "0x<value>"}}}
+    pub fn dump_state(&self) -> serde_json::Value {
+        let addresses: BTreeSet<B160> = self.state.keys()
+            .chain(self.codes.keys())
+            .chain(self.set_balance.keys())
+            .chain(self.nonces.keys())
+            .copied()
+            .collect();
+        let mut alloc = serde_json::Map::new();
+        for address in addresses {
+            let mut account = serde_json::Map::new();
+            if let Some(balance) = self.set_balance.get(&address) {
+                account.insert("balance".to_string(), json!(format!("{:#x}", balance)));
+            }
+            if let Some(nonce) = self.nonces.get(&address) {
+                account.insert("nonce".to_string(), json!(format!("{:#x}", nonce)));
+            }
+            if let Some(code) = self.codes.get(&address) {
+                account.insert("code".to_string(), json!(hex_encode_with_prefix(&code.original_bytes())));
+            }
+            if let Some(storage) = self.state.get(&address) {
+                let storage: BTreeMap<String, String> = storage.iter()
+                    .map(|(slot, value)| (slot_to_str(*slot), slot_to_str(*value)))
+                    .collect();
+                account.insert("storage".to_string(), json!(storage));
+            }
+            alloc.insert(to_str(address), serde_json::Value::Object(account));
+        }
+        serde_json::Value::Object(alloc)
+    }
+
+    // use an `alloc` as the base state, accounts in it are complete except for their storage,
+    // i.e. a missing code, balance or nonce is empty / zero while missing slots are still fetched
+    pub fn load_state(&mut self, alloc: &serde_json::Value) {
+        for (address, account) in alloc.as_object().expect("state must be an object of accounts") {
+            let address = B160::from_str(address.trim_start_matches("0x")).unwrap();
+            self.set_balance.insert(address, account.get("balance").map_or(U256::ZERO, alloc_to_u256));
+            self.nonces.insert(address, account.get("nonce").map_or(0, |nonce| alloc_to_u256(nonce).to::<u64>()));
+            self.codes.insert(address, account.get("code").map_or_else(Bytecode::new, hex_to_code));
+            if let Some(slots) = account.get("storage").and_then(|storage| storage.as_object()) {
+                let storage = self.state.entry(address).or_default();
+                for (slot, value) in slots {
+                    storage.insert(alloc_to_u256(&json!(slot)), alloc_to_u256(value));
+                }
+            }
+        }
+    }
+
+    // account without code, nonce and balance (EIP-161)
+    pub fn is_empty_account(&mut self, address: B160) -> bool {
+        let was_cold = !self.accessed_accounts.contains(&address);
+        let empty = self.code(address).unwrap().0.is_empty()
+            && self.nonce(address) == 0
+            && self.balance(address).unwrap().0 == U256::ZERO;
+        if was_cold {
+            self.accessed_accounts.remove(&address);
+        }
+        empty
+    }
+
+    // derive affected IERC20 accounts and allowances from emitted events
+    pub fn handle_erc20_log(&mut self, token: B160, topics: &[B256]) {
+        if topics.is_empty() {
+            return;
+        }
+        let mut accounts = vec![];
+        match topics[0].0[0..4] {
+            // Transfer(address indexed from, address indexed to, uint256 value), ERC721 has 4 topics
+            [0xdd, 0xf2, 0x52, 0xad] if topics.len() == 3 => {
+                accounts.push(topic_to_address(&topics[1]));
+                accounts.push(topic_to_address(&topics[2]));
+            }
+            // Approval(address indexed owner, address indexed spender, uint256 value)
+            [0x8c, 0x5b, 0xe1, 0xe5] if topics.len() == 3 => {
+                let owner = topic_to_address(&topics[1]);
+                let spender = topic_to_address(&topics[2]);
+                accounts.push(owner);
+                self.erc20_allowances_affected.insert((owner, spender, token));
+            }
+            // Deposit(address indexed dst, uint256 wad), Withdrawal(address indexed src, uint256 wad) (WETH)
+            [0xe1, 0xff, 0xfc, 0xc4] | [0x7f, 0xcf, 0x53, 0x2c] if topics.len() == 2 => {
+                accounts.push(topic_to_address(&topics[1]));
+            }
+            // Sent(address indexed operator, address indexed from, address indexed to, ...) (ERC777)
+            [0x06, 0xb5, 0x41, 0xdd] if topics.len() == 4 => {
+                accounts.push(topic_to_address(&topics[2]));
+                accounts.push(topic_to_address(&topics[3]));
+            }
+            _ => {}
+        }
+        for account in accounts {
+            // skip mint / burn counterparty
+            if account != B160::zero() {
+                self.erc20_affected.insert((account, token));
+            }
+        }
+    }
+
+    // derive ERC721 / ERC1155 transfers from calldata
+    pub fn handle_nft_call(&mut self, token: B160, data: &[u8]) {
+        let from = abi_address_arg(data, 0);
+        let to = abi_address_arg(data, 1);
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return,
+        };
+        match data[0..4] {
+            // safeTransferFrom(address,address,uint256), safeTransferFrom(address,address,uint256,bytes)
+            [0x42, 0x84, 0x2e, 0x0e] | [0xb8, 0x8d, 0x4f, 0xde] if data.len() >= 100 => {
+                let id = U256::from_be_slice(&data[68..100]);
+                self.erc721_affected.insert((token, from, to, id, U256::from(1)));
+            }
+            // safeTransferFrom(address,address,uint256,uint256,bytes)
+            [0xf2, 0x42, 0x43, 0x2a] if data.len() >= 132 => {
+                let id = U256::from_be_slice(&data[68..100]);
+                let amount = U256::from_be_slice(&data[100..132]);
+                self.erc1155_affected.insert((token, from, to, id, amount));
+            }
+            // safeBatchTransferFrom(address,address,uint256[],uint256[],bytes)
+            [0x2e, 0xb2, 0xc2, 0xd6] => {
+                let decoded = ethabi::decode(&[
+                    ethabi::ParamType::Address,
+                    ethabi::ParamType::Address,
+                    ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256))),
+                    ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256))),
+                    ethabi::ParamType::Bytes,
+                ], &data[4..]);
+                if let Ok(decoded) = decoded {
+                    self.handle_erc1155_batch(token, from, to, &decoded[2], &decoded[3]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // derive ERC721 / ERC1155 transfers from emitted events
+    pub fn handle_nft_log(&mut self, token: B160, topics: &[B256], data: &[u8]) {
+        if topics.is_empty() {
+            return;
+        }
+        match topics[0].0[0..4] {
+            // Transfer(address indexed from, address indexed to, uint256 indexed tokenId)
+            [0xdd, 0xf2, 0x52, 0xad] if topics.len() == 4 => {
+                let from = topic_to_address(&topics[1]);
+                let to = topic_to_address(&topics[2]);
+                let id = U256::from_be_bytes(topics[3].0);
+                self.erc721_affected.insert((token, from, to, id, U256::from(1)));
+            }
+            // TransferSingle(address indexed operator, address indexed from, address indexed to, uint256 id, uint256 value)
+            [0xc3, 0xd5, 0x81, 0x68] if topics.len() == 4 && data.len() >= 64 => {
+                let from = topic_to_address(&topics[2]);
+                let to = topic_to_address(&topics[3]);
+                let id = U256::from_be_slice(&data[0..32]);
+                let amount = U256::from_be_slice(&data[32..64]);
+                self.erc1155_affected.insert((token, from, to, id, amount));
+            }
+            // TransferBatch(address indexed operator, address indexed from, address indexed to, uint256[] ids, uint256[] values)
+            [0x4a, 0x39, 0xdc, 0x06] if topics.len() == 4 => {
+                let from = topic_to_address(&topics[2]);
+                let to = topic_to_address(&topics[3]);
+                let decoded = ethabi::decode(&[
+                    ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256))),
+                    ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256))),
+                ], data);
+                if let Ok(decoded) = decoded {
+                    self.handle_erc1155_batch(token, from, to, &decoded[0], &decoded[1]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_erc1155_batch(&mut self, token: B160, from: B160, to: B160, ids: &ethabi::Token, amounts: &ethabi::Token) {
+        if let (ethabi::Token::Array(ids), ethabi::Token::Array(amounts)) = (ids, amounts) {
+            for (id, amount) in ids.iter().zip(amounts.iter()) {
+                if let (ethabi::Token::Uint(id), ethabi::Token::Uint(amount)) = (id, amount) {
+                    self.erc1155_affected.insert((token, from, to, ethabi_uint_to_u256(*id), ethabi_uint_to_u256(*amount)));
+                }
+            }
+        }
+    }
+
+    // precompiles of the spec the interpreter runs with
+    pub fn precompiles(&self) -> &'static Precompiles {
+        Precompiles::new(revm::precompile::SpecId::from_spec_id(self.spec_id))
+    }
+
+    // reset access lists and original storage values for a new transaction
+    pub fn start_transaction(&mut self, caller: B160, target: B160) {
+        self.accessed_accounts.clear();
+        self.accessed_storage.clear();
+        self.original_storage.clear();
+        self.accessed_accounts.insert(caller);
+        self.accessed_accounts.insert(target);
+        for address in self.precompiles().addresses() {
+            self.accessed_accounts.insert(B160::from(*address));
+        }
+    }
+
+    // block context of a block object of eth_getBlockByNumber
+    pub fn set_block_env(&mut self, block: &serde_json::Value) {
+        self.env.block.number = hex_to_u256(&block["number"]);
+        self.env.block.timestamp = hex_to_u256(&block["timestamp"]);
+        self.env.block.coinbase = B160::from_str(block["miner"].as_str().unwrap().trim_start_matches("0x")).unwrap();
+        self.env.block.gas_limit = hex_to_u256(&block["gasLimit"]);
+        self.env.block.difficulty = hex_to_u256(&block["difficulty"]);
+        self.env.block.basefee = block.get("baseFeePerGas").map_or(U256::ZERO, hex_to_u256);
+        self.env.block.prevrandao = block.get("mixHash")
+            .map(|hash| B256::from_str(hash.as_str().unwrap().trim_start_matches("0x")).unwrap());
+        self.update_spec_id();
+    }
+
+    // hardfork of the block env
+    fn update_spec_id(&mut self) {
+        if !self.fixed_spec {
+            let block = (self.env.block.number.to::<u64>(), self.env.block.timestamp.to::<u64>());
+            self.spec_id = get_spec_id(&self.chain, Some(block));
+        }
+    }
+
+    // block context of the block pending on top of the given one, timestamped now as it is being built
+    pub fn set_pending_block_env(&mut self, block: &serde_json::Value) {
+        self.set_block_env(block);
+        self.env.block.number += U256::from(1);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.env.block.timestamp = U256::from(now).max(self.env.block.timestamp + U256::from(1));
+        self.update_spec_id();
+    }
+
+    // run a transaction exactly like test_call does, so that the call traces and affected
+    // accounts / pairs are what the invariants see, returns the result and output of the call
+    pub fn execute_transaction(&mut self, caller: B160, target: B160, data: Bytes, value: U256) -> (InstructionResult, Bytes) {
+        let ctx = B160::from_str("8891e33ba3c6A7b4E020A6180Eb07f4AED2d70CE").unwrap();
+        let input = self.context_abi.function("test_call").unwrap().encode_input(&[
+            ethabi::Token::Address(ethabi::Address::from_slice(&target.0)),
+            ethabi::Token::Address(ethabi::Address::from_slice(&caller.0)),
+            ethabi::Token::Bytes(data.to_vec()),
+            ethabi::Token::Uint(ethabi::Uint::from_big_endian(&value.to_be_bytes::<32>())),
+        ]).unwrap();
+        let (ret, _, out) = self.call(&mut CallInputs {
+            contract: ctx,
+            transfer: Transfer { source: caller, target: ctx, value: U256::ZERO },
+            input: Bytes::from(input),
+            gas_limit: u64::MAX,
+            context: CallContext { address: ctx, caller, code_address: ctx, apparent_value: U256::ZERO, scheme: CallScheme::Call },
+            is_static: false,
+        });
+        // the call information of the Context is that of the transaction
+        self.caller = caller;
+        self.target = target;
+        self.value = value;
+        self.data = data;
+        self.env.tx.caller = caller;
+        let res = match ethabi::decode(&[ethabi::ParamType::Bytes], &out) {
+            Ok(tokens) => Bytes::from(tokens[0].clone().into_bytes().unwrap()),
+            Err(_) => Bytes::new(),
+        };
+        (ret, res)
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            state: self.state.clone(),
+            set_balance: self.set_balance.clone(),
+            nonces: self.nonces.clone(),
+            codes: self.codes.clone(),
+            steps: self.steps.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: StateSnapshot) {
+        self.state = snapshot.state;
+        self.set_balance = snapshot.set_balance;
+        self.nonces = snapshot.nonces;
+        self.codes = snapshot.codes;
+        self.steps = snapshot.steps;
+        self.journal.clear();
+        self.frames = 0;
+    }
+
+    // forget the state of every other account, e.g. when the forked block moves on
+    pub fn retain_accounts(&mut self, accounts: &HashSet<B160>) {
+        self.state.retain(|address, _| accounts.contains(address));
+        self.set_balance.retain(|address, _| accounts.contains(address));
+        self.nonces.retain(|address, _| accounts.contains(address));
+        self.codes.retain(|address, _| accounts.contains(address));
+    }
+
+    // call every invariant_* function of the given specs on the current state,
+    // the state is restored after each of them, so that invariants do not see each other's changes
+    pub fn evaluate_invariants(&mut self, specs: &[B160]) -> Vec<InvariantOutcome> {
+        let mut outcomes = vec![];
+        for spec in specs {
+            let abi = self.abis.get(spec).unwrap().clone();
+            for name in abi.functions.keys().filter(|name| name.starts_with("invariant_")) {
+                let data = abi.function(name).unwrap().encode_input(&[]).unwrap();
+                let snapshot = self.snapshot();
+                self.printed.clear();
+                let caller = generate_random_address();
+                self.start_transaction(caller, *spec);
+                let (result, out) = call_func(self, caller, *spec, Bytes::from(data), U256::ZERO);
+                self.restore(snapshot);
+                let fired = is_call_success(result)
+                    && matches!(ethabi::decode(&[ethabi::ParamType::Bool], &out).as_deref(), Ok([ethabi::Token::Bool(true)]));
+                let output = std::mem::take(&mut self.printed);
+                outcomes.push(InvariantOutcome { spec: *spec, name: name.clone(), result, fired, output });
+            }
+        }
+        outcomes
+    }
+
+    // run a view function on the current state without tracing it
+    pub fn view_call(&mut self, target: B160, data: Vec<u8>) -> Option<Bytes> {
+        let was_inside_contract_call = self.inside_contract_call;
+        self.inside_contract_call = false;
+        // the accounts and slots it reads stay cold for the transaction
+        let accessed_accounts = self.accessed_accounts.clone();
+        let accessed_storage = self.accessed_storage.clone();
+        let (code, _) = self.code(target).unwrap();
+        let contract = Contract {
+            input: Bytes::from(data),
+            bytecode: BytecodeLocked::try_from(code).unwrap(),
+            address: target,
+            caller: target,
+            value: U256::ZERO,
+        };
+        let mut interpreter = Interpreter::new(contract, u64::MAX, true);
+        let ret = run_with_spec!(self.spec_id, interpreter, run, self);
+        self.inside_contract_call = was_inside_contract_call;
+        self.accessed_accounts = accessed_accounts;
+        self.accessed_storage = accessed_storage;
+        if is_call_success(ret) && interpreter.return_value().len() >= 32 {
+            Some(interpreter.return_value())
+        } else {
+            None
+        }
+    }
+
+    // resolve a token of a pool through a getter like token0() or coins(uint256)
+    fn pool_token(&mut self, pool: B160, selector: [u8; 4], index: Option<U256>) -> Option<B160> {
+        let key = (pool, selector, index.unwrap_or_default());
+        if let Some(token) = self.pool_tokens.get(&key) {
+            return Some(*token);
+        }
+        let mut data = selector.to_vec();
+        if let Some(index) = index {
+            data.extend_from_slice(&index.to_be_bytes::<32>());
+        }
+        let token = B160::from_slice(&self.view_call(pool, data)?[12..32]);
+        self.pool_tokens.insert(key, token);
+        Some(token)
+    }
+
+    pub fn swap_token(&mut self, pool: B160, token: SwapToken) -> B160 {
+        let token = match token {
+            SwapToken::Address(address) => Some(address),
+            // token0(), token1()
+            SwapToken::Token0 => self.pool_token(pool, [0x0d, 0xfe, 0x16, 0x81], None),
+            SwapToken::Token1 => self.pool_token(pool, [0xd2, 0x12, 0x20, 0xa7], None),
+            // underlying_coins(uint256)
+            SwapToken::UnderlyingCoin(index) => self.pool_token(pool, [0xb9, 0x94, 0x7e, 0xb0], Some(index)),
+            // coins(uint256), falling back to coins(int128) of older pools
+            SwapToken::Coin(index) => self.pool_token(pool, [0xc6, 0x61, 0x06, 0x57], Some(index))
+                .or_else(|| self.pool_token(pool, [0x23, 0x74, 0x6e, 0xb8], Some(index))),
+        };
+        token.unwrap_or_default()
+    }
+
+    pub fn handle_swap_log(&mut self, address: B160, topics: &[B256], data: &[u8]) {
+        if let Some(swap) = swap_from_log(address, topics, data) {
+            self.pairs_affected.insert(swap.pool);
+            self.swaps_affected.push(swap);
+        }
+    }
+
+    pub fn record_assertion(&mut self, assertion: &str, expected: String, actual: String, message: String, contract: B160) {
+        self.assertion_failures.push(AssertionFailure {
+            assertion: assertion.to_string(),
+            expected,
+            actual,
+            message,
+            test: self.current_test.clone(),
+            contract,
+            trace_index: self.call_traces.len(),
+        });
     }
 }
 
 impl Host for TestHost {
-    fn step(&mut self, interpreter: &mut Interpreter, is_static: bool) -> InstructionResult {
-        if unsafe {*interpreter.instruction_pointer} == 0xfd {
+    fn step(&mut self, interpreter: &mut Interpreter, _is_static: bool) -> InstructionResult {
+        let opcode = unsafe {*interpreter.instruction_pointer};
+        if self.coverage.is_some() {
+            match opcode {
+                // JUMP, JUMPI
+                0x56 | 0x57 => self.jump_from = Some(interpreter.program_counter()),
+                // LT, GT, SLT, SGT: values next to the operands as well, to get on either side
+                0x10..=0x13 => for operand in [interpreter.stack.peek(0), interpreter.stack.peek(1)].into_iter().flatten() {
+                    self.cmp_log.extend([operand, operand.wrapping_add(U256::from(1)), operand.wrapping_sub(U256::from(1))]);
+                },
+                // EQ
+                0x14 => self.cmp_log.extend([interpreter.stack.peek(0), interpreter.stack.peek(1)].into_iter().flatten()),
+                _ => {}
+            }
+        } else if opcode == 0xfd {
+            // reverts of fuzzed calls are expected, so they are only printed in tests
             println!("pc: {}@{:?} reverted {:?}", interpreter.program_counter(), interpreter.contract.address,
                      hex::encode(interpreter.memory.data()));
         }
         InstructionResult::Continue
     }
 
-    fn step_end(&mut self, interpreter: &mut Interpreter, is_static: bool, ret: InstructionResult) -> InstructionResult {
+    fn step_end(&mut self, interpreter: &mut Interpreter, _is_static: bool, _ret: InstructionResult) -> InstructionResult {
+        if let (Some(coverage), Some(from)) = (&mut self.coverage, self.jump_from.take()) {
+            coverage.insert((interpreter.contract.bytecode.hash(), from, interpreter.program_counter()));
+        }
         InstructionResult::Continue
     }
 
@@ -192,20 +1298,25 @@ impl Host for TestHost {
     }
 
     fn load_account(&mut self, address: B160) -> Option<(bool, bool)> {
-        Some((true, true))
+        let is_new_account = !self.precompiles().contains(&address.0) && self.is_empty_account(address);
+        let is_cold = self.accessed_accounts.insert(address);
+        Some((is_cold, is_new_account))
     }
 
     fn block_hash(&mut self, number: U256) -> Option<B256> {
+        self.require_online(|| format!("hash of block {}", number));
         Some(get_block_hash(number))
     }
 
     fn balance(&mut self, address: B160) -> Option<(U256, bool)> {
+        let is_cold = self.accessed_accounts.insert(address);
         match self.set_balance.get(&address) {
-            Some(balance) => Some((*balance, true)),
+            Some(balance) => Some((*balance, is_cold)),
             None => {
+                self.require_online(|| format!("balance of {:?}", address));
                 let balance = get_balance_rpc(address);
                 self.set_balance.insert(address, balance);
-                Some((balance, true))
+                Some((balance, is_cold))
             }
         }
     }
@@ -215,45 +1326,58 @@ impl Host for TestHost {
             return Some((Bytecode::new_raw(Bytes::from(vec![0xfd, 0xfd])), true))
         }
 
+        let is_cold = self.accessed_accounts.insert(address);
         match self.codes.get(&address) {
-            Some(code) => Some((code.clone(), true)),
+            Some(code) => Some((code.clone(), is_cold)),
             None => {
+                self.require_online(|| format!("code of {:?}", address));
                 let code = get_code_rpc(address);
                 self.codes.insert(address, code.clone());
-                Some((code, true))
+                Some((code, is_cold))
             }
         }
     }
 
     fn code_hash(&mut self, address: B160) -> Option<(B256, bool)> {
-        unreachable!("code_hash should not be called")
+        let is_empty = self.is_empty_account(address);
+        let (code, is_cold) = self.code(address).unwrap();
+        if is_empty {
+            return Some((B256::zero(), is_cold));
+        }
+        Some((keccak256(&code.original_bytes()), is_cold))
     }
 
     fn sload(&mut self, address: B160, index: U256) -> Option<(U256, bool)> {
-        match self.state.get_mut(&address) {
-            Some(account) => {
-                if let Some(value) = account.get(&index) {
-                    return Some((*value, true))
-                }
-                let slot_val = get_storage_slot(address, index);
-                account.insert(index, slot_val);
-                return Some((slot_val, true))
-            },
+        let is_cold = self.accessed_storage.insert((address, index));
+        let slot_val = match self.state.get(&address).and_then(|account| account.get(&index)) {
+            Some(value) => *value,
             None => {
-                self.state.insert(address, HashMap::new());
+                self.require_online(|| format!("slot {} of {:?}", index, address));
                 let slot_val = get_storage_slot(address, index);
-                self.state.get_mut(&address).unwrap().insert(index, slot_val);
-                return Some((slot_val, true))
+                self.state.entry(address).or_default().insert(index, slot_val);
+                slot_val
             }
+        };
+        if self.coverage.is_some() {
+            self.cmp_log.insert(slot_val);
         }
+        Some((slot_val, is_cold))
     }
 
     fn sstore(&mut self, address: B160, index: U256, value: U256) -> Option<(U256, U256, U256, bool)> {
+        let (present, is_cold) = self.sload(address, index).unwrap();
+        let original = *self.original_storage.entry((address, index)).or_insert(present);
+        self.journal.push(JournalEntry::Storage(address, index, present));
         self.state.get_mut(&address).unwrap().insert(index, value);
-        Some((U256::ZERO, U256::ZERO, U256::ZERO, true))
+        Some((original, present, value, is_cold))
     }
 
     fn log(&mut self, address: B160, topics: Vec<B256>, data: Bytes) {
+        if self.inside_contract_call {
+            self.handle_erc20_log(address, &topics);
+            self.handle_nft_log(address, &topics, &data);
+            self.handle_swap_log(address, &topics, &data);
+        }
         match self.logs.get_mut(&address) {
             Some(logs) => {
                 logs.push((topics, data));
@@ -264,12 +1388,71 @@ impl Host for TestHost {
         }
     }
 
-    fn selfdestruct(&mut self, address: B160, target: B160) -> Option<SelfDestructResult> {
+    fn selfdestruct(&mut self, _address: B160, _target: B160) -> Option<SelfDestructResult> {
         unreachable!("selfdestruct should not be called")
     }
 
     fn create(&mut self, inputs: &mut CreateInputs) -> (InstructionResult, Option<B160>, Gas, Bytes) {
-        unreachable!("create should not be called")
+        if self.balance(inputs.caller).unwrap().0 < inputs.value {
+            return (InstructionResult::OutOfFund, None, Gas::new(inputs.gas_limit), Bytes::new());
+        }
+        let nonce = self.nonce(inputs.caller);
+        let address = match inputs.scheme {
+            CreateScheme::Create => create_address(inputs.caller, nonce),
+            CreateScheme::Create2 { salt } => create2_address(inputs.caller, keccak256(&inputs.init_code), salt),
+        };
+        // the nonce of the creator is bumped in the calling frame, it stays when the creation fails
+        self.set_nonce_journaled(inputs.caller, nonce + 1);
+        self.accessed_accounts.insert(address);
+        if self.nonce(address) != 0 || !self.code(address).unwrap().0.is_empty() {
+            return (InstructionResult::CreateCollision, None, Gas::new(inputs.gas_limit), Bytes::new());
+        }
+        let checkpoint = self.begin_frame();
+        if SpecId::enabled(self.spec_id, SpecId::SPURIOUS_DRAGON) {
+            self.set_nonce_journaled(address, 1);
+        }
+        if inputs.value != U256::ZERO {
+            self.transfer(&inputs.caller, &address, inputs.value);
+        }
+
+        let contract = Contract {
+            input: Bytes::new(),
+            bytecode: BytecodeLocked::try_from(to_analysed(Bytecode::new_raw(inputs.init_code.clone()))).unwrap(),
+            address,
+            caller: inputs.caller,
+            value: inputs.value,
+        };
+        let mut interpreter = Interpreter::new(contract, inputs.gas_limit, false);
+        self.call_depth += 1;
+        let ret = self.run(&mut interpreter);
+        self.call_depth -= 1;
+        let mut gas = *interpreter.gas();
+        if !is_call_success(ret) {
+            self.end_frame(checkpoint, false);
+            return (ret, None, gas, interpreter.return_value());
+        }
+
+        let code = interpreter.return_value();
+        let failure = if SpecId::enabled(self.spec_id, SpecId::LONDON) && code.first() == Some(&0xef) {
+            // EIP-3541
+            Some(InstructionResult::CreateContractStartingWithEF)
+        } else if SpecId::enabled(self.spec_id, SpecId::SPURIOUS_DRAGON) && code.len() > MAX_CODE_SIZE {
+            // EIP-170
+            Some(InstructionResult::CreateContractSizeLimit)
+        } else if !gas.record_cost(200 * code.len() as u64) {
+            // code deposit cost
+            Some(InstructionResult::OutOfGas)
+        } else {
+            None
+        };
+        if let Some(failure) = failure {
+            self.end_frame(checkpoint, false);
+            return (failure, None, gas, Bytes::new());
+        }
+        self.journal.push(JournalEntry::Code(address, self.codes.get(&address).cloned()));
+        self.codes.insert(address, to_analysed(Bytecode::new_raw(code)));
+        self.end_frame(checkpoint, true);
+        (InstructionResult::Return, Some(address), gas, Bytes::new())
     }
 
     fn call(&mut self, input: &mut CallInputs) -> (InstructionResult, Gas, Bytes) {
@@ -277,19 +1460,59 @@ impl Host for TestHost {
             ($data: expr, $target: expr, $caller: expr) => {
                 {
                     let data_slice = $data.as_slice();
+                    let arg0 = abi_address_arg(data_slice, 0);
+                    let arg1 = abi_address_arg(data_slice, 1);
                     match data_slice[0..4] {
-                        // transfer
-                        [0xa9, 0x05, 0x9c, 0xbb] => {
-                            let dst = B160::from_slice(&data_slice[16..36]);
-                            self.erc20_affected.insert((dst, $target));
+                        // transfer, send (ERC777)
+                        [0xa9, 0x05, 0x9c, 0xbb] | [0x9b, 0xd9, 0xbb, 0xc6] => {
+                            if let Some(dst) = arg0 {
+                                self.erc20_affected.insert((dst, $target));
+                                self.erc20_affected.insert(($caller, $target));
+                            }
+                        }
+                        // transferFrom, operatorSend (ERC777)
+                        [0x23, 0xb8, 0x72, 0xdd] | [0x62, 0xad, 0x1b, 0x83] => {
+                            if let (Some(src), Some(dst)) = (arg0, arg1) {
+                                self.erc20_affected.insert((dst, $target));
+                                self.erc20_affected.insert((src, $target));
+                            }
+                        }
+                        // approve, increaseAllowance, decreaseAllowance
+                        [0x09, 0x5e, 0xa7, 0xb3] | [0x39, 0x50, 0x93, 0x51] | [0xa4, 0x57, 0xc2, 0xd7] => {
+                            if let Some(spender) = arg0 {
+                                self.erc20_affected.insert(($caller, $target));
+                                self.erc20_allowances_affected.insert(($caller, spender, $target));
+                            }
+                        }
+                        // permit
+                        [0xd5, 0x05, 0xac, 0xcf] => {
+                            if let (Some(owner), Some(spender)) = (arg0, arg1) {
+                                self.erc20_affected.insert((owner, $target));
+                                self.erc20_allowances_affected.insert((owner, spender, $target));
+                            }
+                        }
+                        // mint(address,uint256)
+                        [0x40, 0xc1, 0x0f, 0x19] => {
+                            if let Some(dst) = arg0 {
+                                self.erc20_affected.insert((dst, $target));
+                            }
+                        }
+                        // burn(uint256), deposit() and withdraw(uint256) (WETH)
+                        [0x42, 0x96, 0x6c, 0x68] | [0xd0, 0xe3, 0x0d, 0xb0] | [0x2e, 0x1a, 0x7d, 0x4d] => {
                             self.erc20_affected.insert(($caller, $target));
                         }
-                        // transferFrom
-                        [0x23, 0xb8, 0x72, 0xdd] => {
-                            let src = B160::from_slice(&data_slice[12..32]);
-                            let dst = B160::from_slice(&data_slice[48..68]);
-                            self.erc20_affected.insert((dst, $target));
-                            self.erc20_affected.insert((src, $target));
+                        // burn(address,uint256)
+                        [0x9d, 0xc2, 0x9f, 0xac] => {
+                            if let Some(src) = arg0 {
+                                self.erc20_affected.insert((src, $target));
+                            }
+                        }
+                        // burnFrom(address,uint256)
+                        [0x79, 0xcc, 0x67, 0x90] => {
+                            if let Some(src) = arg0 {
+                                self.erc20_affected.insert((src, $target));
+                                self.erc20_allowances_affected.insert((src, $caller, $target));
+                            }
                         }
                         _ => {}
                     };
@@ -302,10 +1525,17 @@ impl Host for TestHost {
                 {
                     let data_slice = $data.as_slice();
                     match data_slice[0..4] {
-                        // swap
-                        [0x02, 0x2c, 0x0d, 0x9f] => {
+                        // swap (Uniswap V2 / Solidly pairs)
+                        [0x02, 0x2c, 0x0d, 0x9f] |
+                        // swap (Uniswap V3 pools)
+                        [0x12, 0x8a, 0xcb, 0x08] |
+                        // exchange / exchange_underlying (Curve pools)
+                        [0x3d, 0xf0, 0x21, 0x24] | [0x5b, 0x41, 0xb9, 0x08] | [0xa6, 0x41, 0x7e, 0xd6] |
+                        [0x65, 0xb2, 0x48, 0x9b] | [0xdd, 0xc1, 0xf5, 0x9d] | [0x39, 0x47, 0x47, 0xc5] => {
                             self.pairs_affected.insert($target);
                         }
+                        // Balancer vault swaps are tracked through the vault's Swap event,
+                        // as the pool is not the call target
                         _ => {}
                     };
                 }
@@ -314,19 +1544,27 @@ impl Host for TestHost {
 
         if input.context.address == B160::from_str("8891e33ba3c6A7b4E020A6180Eb07f4AED2d70CE").unwrap() {
             self.inside_contract_call = false;
+            // Context functions cost no gas apart from the call itself
+            let gas_limit = input.gas_limit;
             // println!("pc: {:?}@{:?}", input.input.to_vec(), input.context.address);
             let function_sig = &input.input.to_vec()[0..4];
             // println!("function_sig: {:?}", hex::encode(function_sig));
 
             let func_name = self.context_mapping.get(function_sig).unwrap();
-            let func = self.context_abi.function(func_name).unwrap();
+
+            if input.is_static && matches!(
+                func_name.as_str(),
+                "set_balance" | "set_nonce" | "buy_token" | "sell_token_to_eth_best_path" | "test_call"
+            ) {
+                return (InstructionResult::StateChangeDuringStaticCall, Gas::new(gas_limit), Bytes::new());
+            }
 
             macro_rules! out_addr {
                 ($addr: expr) => {
                     {
                         let mut out = [0u8; 32];
                         out[12..32].copy_from_slice(&$addr.0);
-                        (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(out.to_vec()))
+                        (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(out.to_vec()))
                     }
                 };
             }
@@ -344,10 +1582,10 @@ impl Host for TestHost {
                 "get_value" => {
                     let mut out = [0u8; 32];
                     out.copy_from_slice(&self.value.to_be_bytes::<32>());
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(out.to_vec()));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(out.to_vec()));
                 },
                 "get_data" => {
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), self.data.clone());
+                    return (InstructionResult::Continue, Gas::new(gas_limit), self.data.clone());
                 },
                 "get_affected_contracts" => {
                     // println!("get_affected_contracts: {:?}", self.call_traces);
@@ -360,7 +1598,36 @@ impl Host for TestHost {
                         )).collect()),
                     ]).to_vec();
                     // println!("get_affected_contracts: {:?}", hex::encode(&encoded));
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(encoded));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "get_call_tree" => {
+                    let encoded = ethabi::encode(&[
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Address(
+                            ethabi::Address::from_slice(&entry.caller.0)
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Address(
+                            ethabi::Address::from_slice(&entry.target.0)
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Uint(
+                            ethabi::Uint::from(scheme_to_u8(entry.scheme))
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Uint(
+                            ethabi::Uint::from_big_endian(&entry.value.to_be_bytes::<32>())
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Uint(
+                            ethabi::Uint::from(entry.depth)
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Bool(
+                            entry.success
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Bytes(
+                            entry.input.to_vec()
+                        )).collect()),
+                        ethabi::Token::Array(self.call_tree.iter().map(|entry| ethabi::Token::Bytes(
+                            entry.output.to_vec()
+                        )).collect()),
+                    ]).to_vec();
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
                 },
                 "get_affected_accounts_ierc20" => {
                     let encoded = ethabi::encode(&[
@@ -371,7 +1638,29 @@ impl Host for TestHost {
                             ethabi::Address::from_slice(&addr.0)
                         })).collect()),
                     ]).to_vec();
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(encoded));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "get_affected_allowances_ierc20" => {
+                    let encoded = ethabi::encode(&[
+                        ethabi::Token::Array(self.erc20_allowances_affected.iter().map(|(owner, _, _)| ethabi::Token::Address({
+                            ethabi::Address::from_slice(&owner.0)
+                        })).collect()),
+                        ethabi::Token::Array(self.erc20_allowances_affected.iter().map(|(_, spender, _)| ethabi::Token::Address({
+                            ethabi::Address::from_slice(&spender.0)
+                        })).collect()),
+                        ethabi::Token::Array(self.erc20_allowances_affected.iter().map(|(_, _, token)| ethabi::Token::Address({
+                            ethabi::Address::from_slice(&token.0)
+                        })).collect()),
+                    ]).to_vec();
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "get_affected_erc721" => {
+                    let encoded = encode_nft_transfers(&self.erc721_affected);
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "get_affected_erc1155" => {
+                    let encoded = encode_nft_transfers(&self.erc1155_affected);
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
                 },
                 "get_affected_pairs" => {
                     let encoded = ethabi::encode(&[
@@ -379,13 +1668,40 @@ impl Host for TestHost {
                             ethabi::Address::from_slice(&addr.0)
                         })).collect())
                     ]).to_vec();
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(encoded));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "get_affected_swaps" => {
+                    // tokens of the pools are read now that the transaction is over
+                    let tokens: Vec<(B160, B160)> = self.swaps_affected.clone().into_iter().map(|swap| {
+                        (self.swap_token(swap.pool, swap.token_in), self.swap_token(swap.pool, swap.token_out))
+                    }).collect();
+                    let encoded = ethabi::encode(&[
+                        ethabi::Token::Array(self.swaps_affected.iter().map(|swap| ethabi::Token::Address(
+                            ethabi::Address::from_slice(&swap.pool.0)
+                        )).collect()),
+                        ethabi::Token::Array(self.swaps_affected.iter().map(|swap| ethabi::Token::Uint(
+                            ethabi::Uint::from(swap.kind as u8)
+                        )).collect()),
+                        ethabi::Token::Array(tokens.iter().map(|(token_in, _)| ethabi::Token::Address(
+                            ethabi::Address::from_slice(&token_in.0)
+                        )).collect()),
+                        ethabi::Token::Array(tokens.iter().map(|(_, token_out)| ethabi::Token::Address(
+                            ethabi::Address::from_slice(&token_out.0)
+                        )).collect()),
+                        ethabi::Token::Array(self.swaps_affected.iter().map(|swap| ethabi::Token::Uint(
+                            ethabi::Uint::from_big_endian(&swap.amount_in.to_be_bytes::<32>())
+                        )).collect()),
+                        ethabi::Token::Array(self.swaps_affected.iter().map(|swap| ethabi::Token::Uint(
+                            ethabi::Uint::from_big_endian(&swap.amount_out.to_be_bytes::<32>())
+                        )).collect()),
+                    ]).to_vec();
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
                 },
                 "contains_swap" => {
                     let encoded = ethabi::encode(&[
-                        ethabi::Token::Bool(self.pairs_affected.len() > 0)
+                        ethabi::Token::Bool(!self.pairs_affected.is_empty())
                     ]).to_vec();
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::from(encoded));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
                 },
                 "call_prev_state" => {
                     let input = self.context_abi.function("call_prev_state")
@@ -401,14 +1717,18 @@ impl Host for TestHost {
                     { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid value") };
                     let temp = self.state.clone();
                     self.state = self.prev_state.clone();
+                    // the changes of the call are undone before the current state is back, so that its journal
+                    // entries (values of the previous state) are not applied to it if an enclosing frame fails
+                    let checkpoint = self.begin_frame();
                     let (ret, res) = call_func(
                         self, caller, target, data, value,
                     );
+                    self.end_frame(checkpoint, false);
                     let encoded_res = ethabi::encode(
                         &[ethabi::Token::Bytes(ethabi::Bytes::from(res.to_vec()))]
                     ).to_vec();
                     self.state = temp;
-                    return (ret, Gas::new(u64::MAX), Bytes::from(encoded_res));
+                    return (ret, Gas::new(gas_limit), Bytes::from(encoded_res));
 
                 },
                 "set_balance" => {
@@ -419,8 +1739,32 @@ impl Host for TestHost {
                     { B160::from(x.0) } else { panic!("invalid account") };
                     let amount = if let ethabi::Token::Uint(x) = input[1]
                     { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid amount") };
-                    self.set_balance.insert(account, amount);
-                    return (InstructionResult::Return, Gas::new(u64::MAX), Bytes::new());
+                    self.set_balance_journaled(account, amount);
+                    self.steps.push(Step::Deal { account, amount });
+                    return (InstructionResult::Return, Gas::new(gas_limit), Bytes::new());
+                },
+                "get_nonce" => {
+                    let input = self.context_abi.function("get_nonce")
+                        .unwrap()
+                        .decode_input(&input.input.to_vec()[4..]).unwrap();
+                    let account = if let ethabi::Token::Address(x) = input[0]
+                    { B160::from(x.0) } else { panic!("invalid account") };
+                    let encoded = ethabi::encode(&[
+                        ethabi::Token::Uint(ethabi::Uint::from(self.nonce(account)))
+                    ]).to_vec();
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::from(encoded));
+                },
+                "set_nonce" => {
+                    let input = self.context_abi.function("set_nonce")
+                        .unwrap()
+                        .decode_input(&input.input.to_vec()[4..]).unwrap();
+                    let account = if let ethabi::Token::Address(x) = input[0]
+                    { B160::from(x.0) } else { panic!("invalid account") };
+                    let nonce = if let ethabi::Token::Uint(x) = input[1]
+                    { x.as_u64() } else { panic!("invalid nonce") };
+                    self.set_nonce_journaled(account, nonce);
+                    self.steps.push(Step::SetNonce { account, nonce });
+                    return (InstructionResult::Return, Gas::new(gas_limit), Bytes::new());
                 },
                 "buy_token" => {
                     let caller = input.context.caller;
@@ -432,17 +1776,26 @@ impl Host for TestHost {
                     let amount = if let ethabi::Token::Uint(x) = input[1]
                     { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid amount") };
 
+                    self.require_online(|| format!("1inch swap of {:?}", token_address));
                     let (value, target, input_bytes) = buy_token(
                         token_address, amount, caller, self.chain.as_str()
                     );
-                    self.add_balance(&target, value);
-                    self.reduce_balance(&caller, value);
+                    let checkpoint = self.begin_frame();
+                    if !self.transfer(&caller, &target, value) {
+                        println!("buy_token: {:?} cannot pay {} wei", caller, value);
+                        self.end_frame(checkpoint, false);
+                        return (InstructionResult::OutOfFund, Gas::new(gas_limit), Bytes::new());
+                    }
 
                     // println!("buy_token: {:?} {:?} {:?}", value, target, hex::encode(input_bytes.clone()));
-                    let (ret, res) = call_func(
-                        self, caller, target, input_bytes, value,
+                    let (ret, _) = call_func(
+                        self, caller, target, input_bytes.clone(), value,
                     );
-                    return (ret, Gas::new(u64::MAX), Bytes::new());
+                    // the swap keeps the ETH if it fails
+                    self.end_frame(checkpoint, is_call_success(ret));
+                    let label = format!("buy_token({:?}, {})", token_address, amount);
+                    self.record_call(caller, target, input_bytes, value, is_call_success(ret), Some(label));
+                    return (ret, Gas::new(gas_limit), Bytes::new());
                 },
                 "sell_token_to_eth_best_path" => {
                     let caller = input.context.caller;
@@ -454,6 +1807,7 @@ impl Host for TestHost {
                     let amount = if let ethabi::Token::Uint(x) = input[1]
                     { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid amount") };
 
+                    self.require_online(|| format!("1inch swap of {:?}", token_address));
                     let transactions = sell_token(
                         token_address, amount, caller, self.chain.as_str()
                     );
@@ -461,15 +1815,17 @@ impl Host for TestHost {
                     let mut ret = InstructionResult::Continue;
                     for (value, target, input_bytes) in transactions {
                         let (_ret, res) = call_func(
-                            self, caller, target, input_bytes, value,
+                            self, caller, target, input_bytes.clone(), value,
                         );
                         ret = _ret;
+                        let label = format!("sell_token_to_eth_best_path({:?}, {})", token_address, amount);
+                        self.record_call(caller, target, input_bytes, value, is_call_success(ret), Some(label));
                         if ret == InstructionResult::Revert {
                             println!("Sell token reverted: {:?}", res);
-                            return (ret, Gas::new(u64::MAX), Bytes::new());
+                            return (ret, Gas::new(gas_limit), Bytes::new());
                         }
                     }
-                    return (ret, Gas::new(u64::MAX), Bytes::new());
+                    return (ret, Gas::new(gas_limit), Bytes::new());
                 },
                 "test_call" => {
                     self.data = input.input.clone();
@@ -479,8 +1835,13 @@ impl Host for TestHost {
                     self.inside_contract_call = true;
                     self.prev_state = self.state.clone();
                     self.call_traces.clear();
+                    self.call_tree.clear();
                     self.erc20_affected.clear();
+                    self.erc20_allowances_affected.clear();
+                    self.erc721_affected.clear();
+                    self.erc1155_affected.clear();
                     self.pairs_affected.clear();
+                    self.swaps_affected.clear();
 
                     // do call
                     let input = self.context_abi.function("test_call")
@@ -495,24 +1856,81 @@ impl Host for TestHost {
                     let value: U256 = if let ethabi::Token::Uint(x) = input[3]
                         { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid value") };
 
-                    self.add_balance(&target, value);
-                    self.reduce_balance(&caller, value);
+                    let (balance, _) = self.balance(caller).unwrap();
+                    if balance < value {
+                        println!("test_call: {:?} cannot pay {} wei", caller, value);
+                        self.inside_contract_call = false;
+                        return (InstructionResult::OutOfFund, Gas::new(gas_limit), Bytes::new());
+                    }
+                    // the nonce of the sender is bumped even if the call fails, the value and the other changes are not kept
+                    let nonce = self.nonce(caller);
+                    self.set_nonce_journaled(caller, nonce + 1);
+                    let checkpoint = self.begin_frame();
+                    self.transfer(&caller, &target, value);
 
                     // erc20 analysis
                     let input_vec = data.to_vec();
                     if input_vec.len() >= 4 {
                         handle_erc20!(input_vec, target, caller);
+                        self.handle_nft_call(target, &input_vec);
                         handle_pairs!(input_vec, target);
                     }
 
-                    let (ret, res) = call_func(
-                        self, caller, target, data, value,
-                    );
+                    self.call_tree.push(CallTraceEntry {
+                        depth: 0,
+                        scheme: CallScheme::Call,
+                        caller,
+                        target,
+                        value,
+                        input: data.clone(),
+                        success: false,
+                        output: Bytes::new(),
+                    });
+                    self.call_depth = 1;
+
+                    // the calls are recorded as sent by the caller of test_call, as reproductions prank it as tx.origin
+                    let origin = std::mem::replace(&mut self.env.tx.caller, caller);
+                    self.start_transaction(caller, target);
+                    self.env.tx.gas_limit = self.gas_limit;
+                    let intrinsic = intrinsic_gas(&data);
+                    let (ret, res, gas) = if GAS_METERED && intrinsic > self.gas_limit {
+                        (InstructionResult::OutOfGas, Bytes::new(), Gas::new(0))
+                    } else {
+                        call_func_with_gas(
+                            self, caller, target, data.clone(), value, self.gas_limit.saturating_sub(intrinsic),
+                        )
+                    };
+                    self.end_frame(checkpoint, is_call_success(ret));
+                    self.call_depth = 0;
+                    self.record_call(caller, target, data.clone(), value, is_call_success(ret), None);
+                    self.env.tx.caller = origin;
+                    if GAS_METERED {
+                        // exceptional halts consume all gas, refunds are capped to a fifth of the gas spent (EIP-3529)
+                        let spent = if is_call_success(ret) || ret == InstructionResult::Revert {
+                            (intrinsic + gas.spend()).min(self.gas_limit)
+                        } else {
+                            self.gas_limit
+                        };
+                        let refunded = if is_call_success(ret) {
+                            (gas.refunded().max(0) as u64).min(spent / 5)
+                        } else {
+                            0
+                        };
+                        self.gas_report.push(GasReportEntry {
+                            target,
+                            input: data,
+                            result: ret,
+                            gas_used: spent - refunded,
+                            refunded,
+                        });
+                    }
+                    self.call_tree[0].success = is_call_success(ret);
+                    self.call_tree[0].output = res.clone();
                     let encoded_res = ethabi::encode(
                         &[ethabi::Token::Bytes(ethabi::Bytes::from(res.to_vec()))]
                     ).to_vec();
                     self.inside_contract_call = false;
-                    return (ret, Gas::new(u64::MAX), Bytes::from(encoded_res));
+                    return (ret, Gas::new(gas_limit), Bytes::from(encoded_res));
                 },
                 "print_int" => {
                     let input = self.context_abi.function("print_int")
@@ -523,29 +1941,92 @@ impl Host for TestHost {
                     let value: U256 = if let ethabi::Token::Uint(x) = input[1]
                         { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid value") };
 
-                    println!("{}: {}", key, value.to_string());
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::new());
+                    self.print(format!("{}: {}", key, value));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
                 },
                 "print_address" => {
                     let input = self.context_abi.function("print_address")
                         .unwrap()
                         .decode_input(&input.input.to_vec()[4..]).unwrap();
-                    let key = if let ethabi::Token::String(x) = input[0].clone()
-                    { x } else { panic!("invalid key") };
-                    let value: B160 = if let ethabi::Token::Address(x) = input[1]
-                        { B160::from(x.0) } else { panic!("invalid value") };
-
-                    println!("{}: {:?}", key, value);
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::new());
+                    let key = if let ethabi::Token::String(x) = input[0].clone()
+                    { x } else { panic!("invalid key") };
+                    let value: B160 = if let ethabi::Token::Address(x) = input[1]
+                        { B160::from(x.0) } else { panic!("invalid value") };
+
+                    self.print(format!("{}: {:?}", key, value));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
+                },
+                "print_string" => {
+                    let input = self.context_abi.function("print_string")
+                        .unwrap()
+                        .decode_input(&input.input.to_vec()[4..]).unwrap();
+                    let value: String = if let ethabi::Token::String(x) = input[0].clone()
+                        { x } else { panic!("invalid value") };
+                    self.print(format!("{:?}", value));
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
+                },
+                "assert_eq" | "assert_le" | "assert_ge" => {
+                    let contract = input.context.caller;
+                    let input = self.context_abi.function(func_name)
+                        .unwrap()
+                        .decode_input(&input.input.to_vec()[4..]).unwrap();
+                    let a: U256 = if let ethabi::Token::Uint(x) = input[0]
+                        { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid a") };
+                    let b: U256 = if let ethabi::Token::Uint(x) = input[1]
+                        { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid b") };
+                    let message = if let ethabi::Token::String(x) = input[2].clone()
+                        { x } else { panic!("invalid message") };
+
+                    let (passed, expected) = match func_name.as_str() {
+                        "assert_eq" => (a == b, b.to_string()),
+                        "assert_le" => (a <= b, format!("<= {}", b)),
+                        _ => (a >= b, format!(">= {}", b)),
+                    };
+                    if !passed {
+                        let assertion = func_name.clone();
+                        self.record_assertion(&assertion, expected, a.to_string(), message, contract);
+                    }
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
+                },
+                "assert_approx_eq_rel" => {
+                    let contract = input.context.caller;
+                    let input = self.context_abi.function("assert_approx_eq_rel")
+                        .unwrap()
+                        .decode_input(&input.input.to_vec()[4..]).unwrap();
+                    let a: U256 = if let ethabi::Token::Uint(x) = input[0]
+                        { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid a") };
+                    let b: U256 = if let ethabi::Token::Uint(x) = input[1]
+                        { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid b") };
+                    // 1e18 == 100%, same as forge-std
+                    let max_delta: U256 = if let ethabi::Token::Uint(x) = input[2]
+                        { U256::from_str(x.to_string().as_str()).unwrap() } else { panic!("invalid max_delta") };
+                    let message = if let ethabi::Token::String(x) = input[3].clone()
+                        { x } else { panic!("invalid message") };
+
+                    if !approx_eq_rel(a, b, max_delta) {
+                        self.record_assertion(
+                            "assert_approx_eq_rel",
+                            format!("{} (max relative delta {} / 1e18)", b, max_delta),
+                            a.to_string(),
+                            message,
+                            contract,
+                        );
+                    }
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
                 },
-                "print_string" => {
-                    let input = self.context_abi.function("print_string")
+                "assert_true" => {
+                    let contract = input.context.caller;
+                    let input = self.context_abi.function("assert_true")
                         .unwrap()
                         .decode_input(&input.input.to_vec()[4..]).unwrap();
-                    let value: String = if let ethabi::Token::String(x) = input[0].clone()
-                        { x } else { panic!("invalid value") };
-                    println!("{:?}", value);
-                    return (InstructionResult::Continue, Gas::new(u64::MAX), Bytes::new());
+                    let condition = if let ethabi::Token::Bool(x) = input[0]
+                        { x } else { panic!("invalid condition") };
+                    let message = if let ethabi::Token::String(x) = input[1].clone()
+                        { x } else { panic!("invalid message") };
+                    if !condition {
+                        self.record_assertion("assert_true", "true".to_string(), "false".to_string(), message, contract);
+                    }
+                    return (InstructionResult::Continue, Gas::new(gas_limit), Bytes::new());
                 },
                 _ => {
                     panic!("unknown function")
@@ -561,21 +2042,81 @@ impl Host for TestHost {
         //     hex::encode(input.input.clone())
         // );
 
+        let precompile = self.precompiles().get(&input.context.code_address.0);
+
+        // DELEGATECALL and CALLCODE run code of another contract on behalf of the current one,
+        // they are only recorded in the call tree
+        let runs_in_caller_context = matches!(input.context.scheme, CallScheme::DelegateCall | CallScheme::CallCode);
+
+        let was_inside_contract_call = self.inside_contract_call;
+        let mut tree_index = None;
         if self.inside_contract_call {
-            self.call_traces.push((input.context.address, input.input.clone()));
-            // erc20 analysis
+            if !runs_in_caller_context {
+                self.call_traces.push((input.context.address, input.input.clone()));
+            }
+            self.call_tree.push(CallTraceEntry {
+                depth: self.call_depth,
+                scheme: input.context.scheme,
+                caller: input.transfer.source,
+                target: input.contract,
+                value: input.transfer.value,
+                input: input.input.clone(),
+                success: false,
+                output: Bytes::new(),
+            });
+            tree_index = Some(self.call_tree.len() - 1);
+            // erc20 analysis, precompile inputs are not ABI encoded calls
             let data = input.input.to_vec();
-            if data.len() >= 4 {
+            if data.len() >= 4 && precompile.is_none() && !runs_in_caller_context {
                 handle_erc20!(data, input.context.address, input.context.caller);
+                self.handle_nft_call(input.context.address, &data);
                 handle_pairs!(data, input.context.address);
             }
         }
 
+        // value, storage and nonces changed by the frame are undone if it does not succeed
+        let checkpoint = self.begin_frame();
+        // value only moves for CALL and CALLCODE (to the calling contract itself),
+        // apparent_value of a DELEGATECALL is the msg.value of the parent frame
+        if input.transfer.value != U256::ZERO
+            && !self.transfer(&input.transfer.source, &input.transfer.target, input.transfer.value) {
+            // the call fails without running, the caller keeps the forwarded gas
+            self.end_frame(checkpoint, false);
+            self.inside_contract_call = was_inside_contract_call;
+            return (InstructionResult::OutOfFund, Gas::new(input.gas_limit), Bytes::new());
+        }
+
+        if let Some(precompile) = precompile {
+            let result = match precompile {
+                Precompile::Standard(fun) => fun(&input.input, input.gas_limit),
+                Precompile::Custom(fun) => fun(&input.input, input.gas_limit),
+            };
+            let mut gas = Gas::new(input.gas_limit);
+            let (ret, output) = match result {
+                Ok((cost, output)) if gas.record_cost(cost) => (InstructionResult::Return, Bytes::from(output)),
+                Ok(_) => (InstructionResult::PrecompileOOG, Bytes::new()),
+                Err(revm::precompile::Error::OutOfGas) => (InstructionResult::PrecompileOOG, Bytes::new()),
+                Err(_) => (InstructionResult::PrecompileError, Bytes::new()),
+            };
+            self.end_frame(checkpoint, is_call_success(ret));
+            if let Some(index) = tree_index {
+                self.call_tree[index].success = is_call_success(ret);
+                self.call_tree[index].output = output.clone();
+            }
+            self.inside_contract_call = was_inside_contract_call;
+            return (ret, gas, output);
+        }
+
+        // first time the contract is entered
+        if !self.codes.contains_key(&input.context.code_address) && !runs_in_caller_context {
+            self.prefetch(input.context.caller, input.context.address, &input.input, input.transfer.value);
+        }
         let code = match self.codes.get(&input.context.code_address) {
             Some(code) => {
                 code.clone()
             }
             None => {
+                self.require_online(|| format!("code of {:?}", input.context.code_address));
                 let code = get_code_rpc(input.context.code_address);
                 self.codes.insert(input.context.code_address, code.clone());
                 code
@@ -588,11 +2129,11 @@ impl Host for TestHost {
             caller: input.context.caller,
             value: input.context.apparent_value,
         };
-        self.add_balance(&input.context.address, input.context.apparent_value);
-        self.reduce_balance(&input.context.caller, input.context.apparent_value);
 
-        let mut interpreter = Interpreter::new(contract, u64::MAX, input.is_static);
-        let ret = interpreter.run::<TestHost, LatestSpec>(self);
+        let mut interpreter = Interpreter::new(contract, input.gas_limit, input.is_static);
+        self.call_depth += 1;
+        let ret = self.run(&mut interpreter);
+        self.call_depth -= 1;
 
         // println!("finish caller: {:?} target: {:?} value: {:?} data: {:?}, {:?}: {:?} ({:?})",
         //          input.context.caller,
@@ -603,24 +2144,222 @@ impl Host for TestHost {
         //          interpreter.return_value(),
         //     hex::encode(interpreter.return_value().to_vec())
         // );
-        self.inside_contract_call = false;
-        (ret, Gas::new(u64::MAX), interpreter.return_value())
+        self.end_frame(checkpoint, is_call_success(ret));
+        if let Some(index) = tree_index {
+            self.call_tree[index].success = is_call_success(ret);
+            self.call_tree[index].output = interpreter.return_value();
+        }
+        // calls a spec makes itself, e.g. approvals in a test
+        if !was_inside_contract_call && !input.is_static && input.context.scheme == CallScheme::Call && self.abis.contains_key(&input.context.caller) {
+            self.record_call(input.context.caller, input.context.address, input.input.clone(), input.transfer.value, is_call_success(ret), None);
+        }
+        // restore instead of clearing, so that calls after a nested call are still traced
+        self.inside_contract_call = was_inside_contract_call;
+        (ret, *interpreter.gas(), interpreter.return_value())
     }
 }
 
+// seeded when recording or replaying, so that a replayed run deploys to and calls from the recorded addresses
+static ADDRESS_RNG: Mutex<Option<StdRng>> = Mutex::new(None);
+const FIXTURE_SEED: u64 = 0;
+
+fn seed_random_addresses(seed: u64) {
+    *ADDRESS_RNG.lock().unwrap() = Some(StdRng::seed_from_u64(seed));
+}
+
 fn generate_random_address() -> B160 {
-    let mut rng = rand::thread_rng();
     let mut address = [0u8; 20];
-    rng.fill_bytes(&mut address);
+    match ADDRESS_RNG.lock().unwrap().as_mut() {
+        Some(rng) => rng.fill_bytes(&mut address),
+        None => rand::thread_rng().fill_bytes(&mut address),
+    }
     B160::from(address)
 }
 
 
+// replay a historical transaction on the state of its parent block and evaluate the invariants of the specs after it
+fn replay_transaction(host: &mut TestHost, tx: &HistoricalTx, specs: &[B160]) -> Vec<InvariantOutcome> {
+    let Some(target) = tx.target else {
+        println!("skipping {:?}: contract creations cannot be replayed", tx.hash);
+        return vec![];
+    };
+    host.gas_limit = tx.gas_limit;
+    host.env.tx.gas_price = tx.gas_price;
+    let (ret, _) = host.execute_transaction(tx.caller, target, tx.data.clone(), tx.value);
+    println!("replayed {:?} from {:?} to {:?}: {:?}, {} calls", tx.hash, tx.caller, target, ret, host.call_tree.len());
+    host.evaluate_invariants(specs)
+}
+
+
+// transactions sent to a watched contract, or in which a watched contract emitted a log
+// (which catches most calls through routers and aggregators)
+fn is_watched(tx: &HistoricalTx, watch: &HashSet<B160>, logged: &HashSet<B256>) -> bool {
+    tx.target.is_some_and(|target| watch.contains(&target)) || logged.contains(&tx.hash)
+}
+
+// replay the watched transactions of blocks from..=to in order, state changes are carried forward,
+// state not touched yet is read at the parent block of the transaction's block,
+// returns the number of replayed transactions and the number of transactions each invariant fired on
+fn replay_block_range(host: &mut TestHost, from: u64, to: u64, watch: &HashSet<B160>, specs: &[B160], alerter: &Alerter) -> (usize, BTreeMap<String, usize>) {
+    let logged = get_logged_transactions(from, to, watch);
+    let mut replayed = 0;
+    let mut fired: BTreeMap<String, usize> = BTreeMap::new();
+    for number in from..=to {
+        let block = get_block(number, true);
+        let txs: Vec<HistoricalTx> = block["transactions"].as_array().unwrap().iter()
+            .map(HistoricalTx::from_json)
+            .filter(|tx| is_watched(tx, watch, &logged))
+            .collect();
+        if txs.is_empty() {
+            continue;
+        }
+        rpc::set_fork_block(Some(number - 1));
+        host.set_block_env(&block);
+        // a reproduction of a transaction starts with the watched transactions before it in the block
+        host.steps.clear();
+        for tx in txs {
+            let outcomes = replay_transaction(host, &tx, specs);
+            replayed += 1;
+            for outcome in outcomes {
+                if outcome.fired || !is_call_success(outcome.result) {
+                    println!("{}", outcome);
+                }
+                if outcome.fired {
+                    alerter.invariant_fired(host, &tx, &outcome, "blocks");
+                }
+                *fired.entry(outcome.name).or_default() += outcome.fired as usize;
+            }
+        }
+    }
+    (replayed, fired)
+}
+
+
+// simulate a pending transaction on the current state and evaluate the invariants of the specs if it
+// called one of the watched contracts (any transaction if none are watched), the state is not restored
+fn simulate_pending_transaction(host: &mut TestHost, tx: &HistoricalTx, watch: &HashSet<B160>, specs: &[B160]) -> Option<Vec<InvariantOutcome>> {
+    let target = tx.target?;
+    host.gas_limit = tx.gas_limit;
+    host.env.tx.gas_price = tx.gas_price;
+    host.execute_transaction(tx.caller, target, tx.data.clone(), tx.value);
+    let relevant = watch.is_empty() || host.call_tree.iter().any(|call| watch.contains(&call.target));
+    relevant.then(|| host.evaluate_invariants(specs))
+}
+
+// pending transactions since the last poll, through a newPendingTransactions filter
+// or, for nodes without filters, from the pending block
+// filter of new pending transactions, None if the node does not support them
+fn new_pending_filter() -> Result<Option<serde_json::Value>, String> {
+    match rpc::try_request("eth_newPendingTransactionFilter", json!([])) {
+        Ok(filter) => Ok(Some(filter)),
+        Err(rpc::RpcError::Unsupported) => {
+            println!("pending transaction filters are not supported, polling the pending block");
+            Ok(None)
+        }
+        Err(rpc::RpcError::Other(error)) => Err(format!("eth_newPendingTransactionFilter failed: {}", error)),
+    }
+}
+
+fn poll_pending_transactions(filter: &mut Option<serde_json::Value>, seen: &mut HashSet<B256>) -> Result<Vec<HistoricalTx>, String> {
+    let txs: Vec<serde_json::Value> = match filter.clone() {
+        Some(id) => {
+            let hashes = match rpc::request("eth_getFilterChanges", json!([id])) {
+                Ok(hashes) => hashes,
+                Err(error) => {
+                    // nodes drop filters when they restart or after a while without polling,
+                    // the old filter is kept to try again on the next poll if a new one cannot be created
+                    println!("{}, recreating the pending transaction filter", error);
+                    *filter = new_pending_filter()?;
+                    return Ok(vec![]);
+                }
+            };
+            let requests: Vec<(&str, serde_json::Value)> = hashes.as_array().unwrap().iter()
+                .map(|hash| ("eth_getTransactionByHash", json!([hash])))
+                .collect();
+            // transactions may have been dropped or mined in the meantime
+            rpc::batch_request(&requests).into_iter().flatten().filter(|tx| !tx.is_null()).collect()
+        }
+        None => {
+            let block = rpc::request("eth_getBlockByNumber", json!(["pending", true]))?;
+            block["transactions"].as_array().cloned().unwrap_or_default()
+        }
+    };
+    Ok(txs.iter().map(HistoricalTx::from_json).filter(|tx| seen.insert(tx.hash)).collect())
+}
+
+// simulate every new pending transaction on top of the latest block until interrupted, alerting on invariants that fire
+fn monitor(host: &mut TestHost, watch: &HashSet<B160>, specs: &[B160], interval: Duration, alerter: &Alerter) {
+    let mut filter = new_pending_filter().unwrap_or_else(|error| {
+        println!("{}, polling the pending block", error);
+        None
+    });
+    // spec accounts keep the state of their deployment
+    let keep: HashSet<B160> = specs.iter().copied().collect();
+    let mut latest = 0;
+    let mut seen = HashSet::new();
+    loop {
+        // the node may be unavailable for a while, the monitor keeps polling
+        let polled = rpc::request("eth_blockNumber", json!([])).and_then(|number| {
+            let number = hex_to_u64(&number);
+            if number != latest {
+                // behind a load balancer the node answering may not have the block yet, it is fetched again on the next poll
+                let block = rpc::request("eth_getBlockByNumber", json!([format!("{:#x}", number), false]))?;
+                if block.is_null() {
+                    return Err(format!("block {} not found", number));
+                }
+                rpc::set_fork_block(Some(number));
+                host.retain_accounts(&keep);
+                host.steps.clear();
+                host.set_pending_block_env(&block);
+                latest = number;
+                seen.clear();
+                println!("monitoring on top of block {}", latest);
+            }
+            poll_pending_transactions(&mut filter, &mut seen)
+        });
+        let txs = polled.unwrap_or_else(|error| {
+            println!("polling failed: {}", error);
+            vec![]
+        });
+        for tx in txs {
+            // pending transactions are simulated independently of each other, a failing one must not stop the monitor
+            let snapshot = host.snapshot();
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                simulate_pending_transaction(host, &tx, watch, specs)
+            }));
+            host.restore(snapshot);
+            host.inside_contract_call = false;
+            host.call_depth = 0;
+            match result {
+                Ok(Some(outcomes)) => {
+                    for outcome in outcomes.iter().filter(|outcome| outcome.fired) {
+                        println!("ALERT: {} fired on pending transaction {:?} from {:?}", outcome.name, tx.hash, tx.caller);
+                        alerter.invariant_fired(host, &tx, outcome, "monitor");
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => println!("simulating {:?} failed", tx.hash),
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+
 fn call_func(host: &mut TestHost, caller: B160, target: B160, data: Bytes, value: U256) -> (InstructionResult, Bytes) {
+    let (ret, res, _) = call_func_with_gas(host, caller, target, data, value, u64::MAX);
+    (ret, res)
+}
+
+fn call_func_with_gas(
+    host: &mut TestHost, caller: B160, target: B160, data: Bytes, value: U256, gas_limit: u64,
+) -> (InstructionResult, Bytes, Gas) {
     host.origin = target;
+    host.prefetch(caller, target, &data, value);
     host.logs.clear();
     let code = match host.codes.get(&target) {
         None => {
+            host.require_online(|| format!("code of {:?}", target));
             let code = get_code_rpc(target);
             host.codes.insert(target, code.clone());
             code
@@ -637,9 +2376,11 @@ fn call_func(host: &mut TestHost, caller: B160, target: B160, data: Bytes, value
         caller,
         value,
     };
-    let mut interpreter = Interpreter::new(contract, u64::MAX, false);
-    let ret = interpreter.run_inspect::<TestHost, LatestSpec>(host);
-    return (ret, interpreter.return_value());
+    let mut interpreter = Interpreter::new(contract, gas_limit, false);
+    let checkpoint = host.begin_frame();
+    let ret = run_with_spec!(host.spec_id, interpreter, run_inspect, host);
+    host.end_frame(checkpoint, is_call_success(ret));
+    (ret, interpreter.return_value(), *interpreter.gas())
 }
 
 
@@ -650,55 +2391,128 @@ fn load_context_abi() -> (HashMap<[u8; 4], String>, ethabi::Contract) {
     let abis = ethabi::Contract::load(file).unwrap();
     for func in abis.functions() {
         let sig = func.short_signature();
-        unsafe {
-            result.insert(sig, func.name.to_string());
-        }
+        result.insert(sig, func.name.to_string());
     }
     (result, abis)
 }
 
 
 fn main() {
-    let mut args = env::args();
-    if args.len() < 3 {
-        println!("Usage: {} <glob> <chain>", args.nth(0).unwrap());
+    let args: Vec<String> = env::args().collect();
+    // positional arguments, `--name value` options and the value-less flags below
+    const FLAGS: [&str; 2] = ["offline", "monitor"];
+    let mut positional = vec![];
+    let mut options = HashMap::new();
+    let mut flags = HashSet::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].strip_prefix("--") {
+            Some(name) if FLAGS.contains(&name) => {
+                flags.insert(name.to_string());
+                i += 1;
+            }
+            Some(name) => {
+                let value = args.get(i + 1).unwrap_or_else(|| panic!("missing value for --{}", name));
+                options.insert(name.to_string(), value.clone());
+                i += 2;
+            }
+            None => {
+                positional.push(args[i].clone());
+                i += 1;
+            }
+        }
+    }
+    if positional.len() < 2 {
+        println!("Usage: {} <glob> <chain> [--rpc <url,...>] [--gas-limit <gas>] [--spec <hardfork>] [--offline] [--inch-api <url>] [--record <file> | --replay <file>] [--load-state <file>] [--dump-state <file>] [--replay-tx <hash> | --blocks <from>:<to> --watch <address,...> | --monitor [--watch <address,...>] [--poll-interval <ms>]] [--alert <sink,...>] [--foundry <dir>] [--fuzz <address[:abi],...> [--iterations <n>] [--sequence-length <n>] [--senders <n>] [--seed <n>] [--corpus <dir>]]", args[0]);
         return;
     }
 
 
     let (context_info, context_abi) = load_context_abi();
     // println!("{:?}", context_info);
-    args.next();
-    let path = args.next().unwrap();
-    let chain = args.next().unwrap();
+    let path = positional[0].clone();
+    let chain = positional[1].clone();
     println!("path: {}, chain: {}", path, chain);
     // glob pattern
 
-    unsafe {
-        RPC_URL = get_rpc_url(chain.clone());
+    // endpoints from --rpc or RPC_URLS (comma separated) replace the public ones
+    let rpc_urls: Vec<String> = match options.get("rpc").cloned().or_else(|| env::var("RPC_URLS").ok()) {
+        Some(urls) => urls.split(',').map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect(),
+        None => get_rpc_urls(chain.clone()).into_iter().map(|url| url.to_string()).collect(),
+    };
+    rpc::set_endpoints(rpc_urls);
+    if let Some(url) = options.get("inch-api") {
+        liquidation::set_inch_api(url.clone());
+    }
+    match (options.get("record"), options.get("replay")) {
+        (Some(_), Some(_)) => {
+            println!("--record and --replay cannot be used together");
+            return;
+        }
+        (Some(_), None) => {
+            rpc::start_recording();
+            seed_random_addresses(FIXTURE_SEED);
+        }
+        (None, Some(path)) => {
+            rpc::replay(fixtures::Fixtures::load(path));
+            seed_random_addresses(FIXTURE_SEED);
+        }
+        (None, None) => {}
     }
     let mut invariant_deployed_addresses = Vec::new();
-    let mut host = TestHost {
-        state: Default::default(),
-        prev_state: Default::default(),
-        call_traces: vec![],
-        erc20_affected: Default::default(),
-        pairs_affected: Default::default(),
-        env: Default::default(),
-        logs: Default::default(),
-        codes: Default::default(),
-        abis: Default::default(),
-        context_mapping: context_info,
-        context_abi,
-        origin: Default::default(),
-        caller: Default::default(),
-        value: Default::default(),
-        data: Default::default(),
-        target: Default::default(),
-        inside_contract_call: false,
-        chain,
-        set_balance: Default::default(),
-    };
+    let mut host = TestHost::new(context_info, context_abi, chain);
+    if let Some(gas_limit) = options.get("gas-limit") {
+        if !GAS_METERED {
+            println!("--gas-limit requires the gas metered build (--no-default-features)");
+            return;
+        }
+        host.gas_limit = gas_limit.parse().expect("invalid gas limit");
+    }
+    if let Some(spec) = options.get("spec") {
+        host.spec_id = parse_spec_id(spec);
+        host.fixed_spec = true;
+    }
+    if flags.contains("offline") {
+        host.offline = true;
+        host.prefetch = false;
+    }
+    let modes = [options.contains_key("replay-tx"), options.contains_key("blocks"), flags.contains("monitor"), options.contains_key("fuzz")];
+    if modes.iter().filter(|mode| **mode).count() > 1 {
+        println!("--replay-tx, --blocks, --monitor and --fuzz cannot be used together");
+        return;
+    }
+    // with --replay-tx, the state is forked at the parent block of the transaction
+    let replayed_tx = options.get("replay-tx").map(|hash| {
+        let tx = get_transaction(hash);
+        rpc::set_fork_block(Some(tx.block - 1));
+        host.set_block_env(&get_block(tx.block, false));
+        println!("forking block {} to replay {:?}", tx.block - 1, tx.hash);
+        tx
+    });
+    // with --blocks, the state is forked at the parent block of the range
+    let block_range = options.get("blocks").map(|range| {
+        let (from, to) = range.split_once(':').expect("block range must be <from>:<to>");
+        let (from, to): (u64, u64) = (from.parse().expect("invalid block"), to.parse().expect("invalid block"));
+        assert!(0 < from && from <= to, "invalid block range {}", range);
+        rpc::set_fork_block(Some(from - 1));
+        host.set_block_env(&get_block(from - 1, false));
+        println!("forking block {} to replay blocks {} to {}", from - 1, from, to);
+        (from, to)
+    });
+    let watch: HashSet<B160> = options.get("watch").map_or_else(HashSet::new, |addresses| {
+        addresses.split(',').map(|address| B160::from_str(address.trim().trim_start_matches("0x")).expect("invalid address")).collect()
+    });
+    if block_range.is_some() && watch.is_empty() {
+        println!("--blocks requires the contracts to --watch");
+        return;
+    }
+    if let Some(path) = options.get("load-state") {
+        let file = File::open(path).unwrap_or_else(|e| panic!("cannot open state {}: {}", path, e));
+        let state: serde_json::Value = serde_json::from_reader(file).unwrap();
+        host.load_state(&state);
+        println!("loaded state of {} accounts from {}", state.as_object().unwrap().len(), path);
+    }
+    println!("spec: {:?}", host.spec_id);
 
     let mut name_to_abi = HashMap::new();
     let mut name_to_address = HashMap::new();
@@ -724,7 +2538,7 @@ fn main() {
                         if obj.get("type").unwrap().as_str().unwrap() != "function" {
                             continue;
                         }
-                        let name = obj.get("name").unwrap().as_str().unwrap();
+                        let _name = obj.get("name").unwrap().as_str().unwrap();
                     }
                 } else if seg.ends_with(".bin") {
                     let deploy_address = generate_random_address();
@@ -738,7 +2552,7 @@ fn main() {
                     let mut file = File::open(path).unwrap();
                     let mut contents = String::new();
                     file.read_to_string(&mut contents).unwrap();
-                    let mut contract_code = hex::decode(contents.trim()).unwrap();
+                    let contract_code = hex::decode(contents.trim()).unwrap();
                     let bytes = Bytes::from(contract_code);
                     let bytecode = BytecodeLocked::try_from(
                         to_analysed(Bytecode::new_raw(bytes))
@@ -752,9 +2566,10 @@ fn main() {
                         value: U256::ZERO,
                     };
                     let mut interpreter = Interpreter::new(contract, u64::MAX, false);
-                    let ret = interpreter.run_inspect::<TestHost, LatestSpec>(&mut host);
+                    let ret = run_with_spec!(host.spec_id, interpreter, run_inspect, &mut host);
                     assert_ne!(ret, InstructionResult::Revert);
                     invariant_deployed_addresses.push(deploy_address);
+                    host.nonces.insert(deploy_address, 1);
                     host.codes.insert(deploy_address, to_analysed(
                         Bytecode::new_raw(interpreter.return_value())
                     ));
@@ -770,23 +2585,1390 @@ fn main() {
         host.abis.insert(*address, abi);
     }
 
-    for addr in invariant_deployed_addresses {
-        let abi = host.abis.get(&addr).unwrap().clone();
-        for (name, _) in &abi.functions {
+    // --alert stdout,file:<path>,webhook:<url>,...
+    let alerter = Alerter {
+        sinks: options.get("alert").map_or_else(Vec::new, |sinks| sinks.split(',').map(Sink::parse).collect()),
+        foundry: options.get("foundry").cloned(),
+        spec_names: name_to_address.iter().map(|(name, address)| {
+            (*address, name.rsplit('/').next().unwrap().to_string())
+        }).collect(),
+    };
 
-            if name.starts_with("test_") {
-                let func = abi.function(name.as_str()).unwrap();
-                let data = func.encode_input(&[]).unwrap().to_vec();
-                let (ret, res) = call_func(&mut host, generate_random_address(),
-                                           addr.clone(), Bytes::from(data), U256::ZERO);
-                println!("calling {:?} @ {:?}, ret: {:?}, res: {:?}", name, addr, ret, res);
-                if ret == InstructionResult::Stop || ret == InstructionResult::Return {
-                    println!("Test passed!");
+    if let Some(tx) = &replayed_tx {
+        host.steps.clear();
+        let outcomes = replay_transaction(&mut host, tx, &invariant_deployed_addresses);
+        for outcome in &outcomes {
+            println!("{}", outcome);
+            if outcome.fired {
+                alerter.invariant_fired(&host, tx, outcome, "replay-tx");
+            }
+        }
+        let fired = outcomes.iter().filter(|outcome| outcome.fired).count();
+        println!("{} of {} invariants fired", fired, outcomes.len());
+    } else if let Some(targets) = options.get("fuzz") {
+        let targets: Vec<FuzzTarget> = targets.split(',').map(|target| FuzzTarget::parse(target.trim(), &host.chain)).collect();
+        let seed = options.get("seed").map_or_else(rand::random, |seed| seed.parse().expect("invalid seed"));
+        let iterations = options.get("iterations").map_or(1000, |n| n.parse().expect("invalid iterations"));
+        let sequence_length = options.get("sequence-length").map_or(8, |n| n.parse().expect("invalid sequence length"));
+        let senders = options.get("senders").map_or(3, |n| n.parse().expect("invalid senders"));
+        println!("fuzzing {} targets with seed {}", targets.len(), seed);
+        let mut fuzzer = Fuzzer::new(targets, &mut host, senders, sequence_length, seed);
+        if let Some(dir) = options.get("corpus") {
+            fuzzer.load_corpus(&mut host, dir);
+            println!("loaded {} sequences covering {} edges from {}", fuzzer.corpus.entries.len(), fuzzer.corpus.coverage.len(), dir);
+        }
+        let findings = fuzzer.fuzz(&mut host, &invariant_deployed_addresses, iterations, &alerter);
+        println!("fuzzed {} sequences of up to {} transactions, {} invariants fired", iterations, sequence_length, findings.len());
+        println!("corpus: {} sequences covering {} edges", fuzzer.corpus.entries.len(), fuzzer.corpus.coverage.len());
+        for finding in &findings {
+            println!("{} (found after {} transactions, minimized to {})", finding.outcome, finding.found_length, finding.sequence.len());
+            for call in &finding.sequence {
+                println!("        {}", call);
+            }
+        }
+    } else if flags.contains("monitor") {
+        let interval = options.get("poll-interval").map_or(1000, |ms| ms.parse().expect("invalid poll interval"));
+        monitor(&mut host, &watch, &invariant_deployed_addresses, Duration::from_millis(interval), &alerter);
+    } else if let Some((from, to)) = block_range {
+        let (replayed, fired) = replay_block_range(&mut host, from, to, &watch, &invariant_deployed_addresses, &alerter);
+        println!("replayed {} transactions of blocks {} to {}", replayed, from, to);
+        for (name, count) in fired {
+            println!("    {} fired on {} of {} transactions", name, count, replayed);
+        }
+    } else {
+        for addr in invariant_deployed_addresses {
+            let abi = host.abis.get(&addr).unwrap().clone();
+            for name in abi.functions.keys() {
+
+                if name.starts_with("test_") {
+                    let func = abi.function(name.as_str()).unwrap();
+                    let data = func.encode_input(&[]).unwrap().to_vec();
+                    host.current_test = name.clone();
+                    host.assertion_failures.clear();
+                    host.printed.clear();
+                    host.gas_report.clear();
+                    host.steps.clear();
+                    let caller = generate_random_address();
+                    host.env.tx.caller = caller;
+                    host.start_transaction(caller, addr);
+                    // a test that cannot fetch the forked state it needs fails on its own
+                    let (ret, res) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        call_func(&mut host, caller, addr, Bytes::from(data), U256::ZERO)
+                    })).unwrap_or_else(|_| {
+                        host.abort_transaction();
+                        (InstructionResult::FatalExternalError, Bytes::new())
+                    });
+                    println!("calling {:?} @ {:?}, ret: {:?}, res: {:?}", name, addr, ret, res);
+                    for failure in &host.assertion_failures {
+                        println!("{}", failure);
+                    }
+                    if GAS_METERED && !host.gas_report.is_empty() {
+                        println!("gas report of {}:", name);
+                        for entry in &host.gas_report {
+                            println!("{}", entry);
+                        }
+                    }
+                    if (ret == InstructionResult::Stop || ret == InstructionResult::Return)
+                        && host.assertion_failures.is_empty() {
+                        println!("Test passed!");
+                    } else {
+                        println!("Test failed!");
+                        if let Some(dir) = options.get("foundry") {
+                            let reproduction = Reproduction::new(&host, &alerter.spec_names[&addr], name, &host.printed);
+                            println!("exported {}", reproduction.save(dir));
+                        }
+                    }
                 }
             }
         }
     }
 
+    if let Some(path) = options.get("record") {
+        let recording = rpc::take_recording().unwrap();
+        recording.save(path);
+        println!("recorded {} responses to {}", recording.rpc.len() + recording.http.len(), path);
+    }
+    if let Some(path) = options.get("dump-state") {
+        let state = host.dump_state();
+        let mut file = File::create(path).unwrap_or_else(|e| panic!("cannot create state {}: {}", path, e));
+        writeln!(file, "{}", serde_json::to_string_pretty(&state).unwrap()).unwrap();
+        println!("dumped state of {} accounts to {}", state.as_object().unwrap().len(), path);
+    }
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::primitives::KECCAK_EMPTY;
+    use crate::fuzzer::FuzzCall;
+    use crate::minimizer::minimize;
+    use crate::mock_server;
+
+    // test host with the given contracts deployed with empty slots 0 and 1 and every account funded,
+    // so that nothing has to be fetched from RPC
+    fn test_host(contracts: Vec<(B160, Vec<u8>)>, accounts: Vec<B160>) -> TestHost {
+        let (context_info, context_abi) = load_context_abi();
+        let mut host = TestHost::new(context_info, context_abi, "ETH".to_string());
+        host.prefetch = false;
+        for (address, code) in contracts {
+            host.codes.insert(address, to_analysed(Bytecode::new_raw(Bytes::from(code))));
+            host.state.insert(address, HashMap::from([(U256::ZERO, U256::ZERO), (U256::from(1), U256::ZERO)]));
+            host.set_balance.insert(address, U256::from(100));
+            host.nonces.insert(address, 1);
+        }
+        for account in accounts {
+            host.set_balance.insert(account, U256::from(100));
+            host.nonces.insert(account, 0);
+            host.codes.insert(account, Bytecode::new());
+        }
+        host
+    }
+
+    // <push zeros> PUSH20 target GAS <opcode> [PUSH1 slot SSTORE] STOP
+    fn call_code(opcode: u8, target: B160, value: Option<u8>, result_slot: Option<u8>) -> Vec<u8> {
+        // retSize, retOffset, argsSize, argsOffset
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+        if let Some(value) = value {
+            code.extend_from_slice(&[0x60, value]);
+        }
+        code.push(0x73);
+        code.extend_from_slice(&target.0);
+        code.extend_from_slice(&[0x5a, opcode]);
+        match result_slot {
+            Some(slot) => code.extend_from_slice(&[0x60, slot, 0x55, 0x00]),
+            None => code.extend_from_slice(&[0x50, 0x00]),
+        }
+        code
+    }
+
+    // PUSH1 0x2a PUSH1 0x00 SSTORE STOP
+    const STORE_42: [u8; 6] = [0x60, 0x2a, 0x60, 0x00, 0x55, 0x00];
+
+    #[test]
+    fn test_delegatecall_uses_caller_storage() {
+        let (caller, proxy, implementation) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (proxy, call_code(0xf4, implementation, None, None)),
+            (implementation, STORE_42.to_vec()),
+        ], vec![caller]);
+        host.inside_contract_call = true;
+
+        let (ret, _) = call_func(&mut host, caller, proxy, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&proxy].get(&U256::ZERO), Some(&U256::from(42)));
+        assert_eq!(host.state[&implementation].get(&U256::ZERO), Some(&U256::ZERO));
+
+        // only recorded in the call tree
+        assert!(host.call_traces.is_empty());
+        assert_eq!(host.call_tree.len(), 1);
+        assert_eq!(host.call_tree[0].scheme, CallScheme::DelegateCall);
+        assert_eq!(host.call_tree[0].caller, proxy);
+        assert_eq!(host.call_tree[0].target, implementation);
+        assert!(host.call_tree[0].success);
+    }
+
+    #[test]
+    fn test_delegatecall_does_not_move_value() {
+        let (caller, sender, proxy, implementation) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001),
+            B160::from_low_u64_be(0x1002), B160::from_low_u64_be(0x1003),
+        );
+        let mut host = test_host(vec![
+            (sender, call_code(0xf1, proxy, Some(5), None)),
+            (proxy, call_code(0xf4, implementation, None, None)),
+            (implementation, vec![0x00]),
+        ], vec![caller]);
+
+        let (ret, _) = call_func(&mut host, caller, sender, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.set_balance[&sender], U256::from(95));
+        assert_eq!(host.set_balance[&proxy], U256::from(105));
+        assert_eq!(host.set_balance[&implementation], U256::from(100));
+    }
+
+    #[test]
+    fn test_callcode_uses_caller_storage_and_balance() {
+        let (caller, contract, library) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (contract, call_code(0xf2, library, Some(5), None)),
+            (library, STORE_42.to_vec()),
+        ], vec![caller]);
+
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&contract].get(&U256::ZERO), Some(&U256::from(42)));
+        assert_eq!(host.state[&library].get(&U256::ZERO), Some(&U256::ZERO));
+        // value is sent to the calling contract itself
+        assert_eq!(host.set_balance[&contract], U256::from(100));
+        assert_eq!(host.set_balance[&library], U256::from(100));
+    }
+
+    #[test]
+    fn test_staticcall_rejects_state_changes() {
+        let (caller, contract, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (contract, call_code(0xfa, target, None, Some(1))),
+            (target, STORE_42.to_vec()),
+        ], vec![caller]);
+        host.state.get_mut(&contract).unwrap().insert(U256::from(1), U256::from(7));
+
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        // STATICCALL pushed 0
+        assert_eq!(host.state[&contract].get(&U256::from(1)), Some(&U256::ZERO));
+        assert_eq!(host.state[&target].get(&U256::ZERO), Some(&U256::ZERO));
+    }
+
+    #[test]
+    fn test_call_moves_value_and_uses_callee_storage() {
+        let (caller, contract, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (contract, call_code(0xf1, target, Some(5), Some(1))),
+            (target, STORE_42.to_vec()),
+        ], vec![caller]);
+
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&contract].get(&U256::from(1)), Some(&U256::from(1)));
+        assert_eq!(host.state[&target].get(&U256::ZERO), Some(&U256::from(42)));
+        assert_eq!(host.set_balance[&contract], U256::from(95));
+        assert_eq!(host.set_balance[&target], U256::from(105));
+    }
+    // PUSH1 0x2a PUSH1 0x00 SSTORE PUSH1 0x00 PUSH1 0x00 REVERT
+    const STORE_42_AND_REVERT: [u8; 10] = [0x60, 0x2a, 0x60, 0x00, 0x55, 0x60, 0x00, 0x60, 0x00, 0xfd];
+
+    #[test]
+    fn test_reverted_call_undoes_value_and_storage() {
+        let (caller, contract, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (contract, call_code(0xf1, target, Some(5), Some(1))),
+            (target, STORE_42_AND_REVERT.to_vec()),
+        ], vec![caller]);
+        host.state.get_mut(&contract).unwrap().insert(U256::from(1), U256::from(7));
+
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        // CALL pushed 0
+        assert_eq!(host.state[&contract][&U256::from(1)], U256::ZERO);
+        assert_eq!(host.state[&target][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.set_balance[&contract], U256::from(100));
+        assert_eq!(host.set_balance[&target], U256::from(100));
+        assert!(host.journal.is_empty());
+    }
+
+    #[test]
+    fn test_reverted_frame_undoes_deals_and_nonces() {
+        let (spec, known, unknown) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![], vec![spec, known]);
+
+        let checkpoint = host.begin_frame();
+        for account in [known, unknown] {
+            call_context(&mut host, spec, "set_balance", &[address(account), uint(U256::from(5))]);
+            call_context(&mut host, spec, "set_nonce", &[address(account), uint(U256::from(9))]);
+        }
+        assert_eq!((host.set_balance[&unknown], host.nonces[&unknown]), (U256::from(5), 9));
+        host.end_frame(checkpoint, false);
+        assert_eq!((host.set_balance[&known], host.nonces[&known]), (U256::from(100), 0));
+        assert!(!host.set_balance.contains_key(&unknown) && !host.nonces.contains_key(&unknown));
+    }
+
+    #[test]
+    fn test_call_prev_state_does_not_leak_into_the_journal() {
+        let (spec, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let mut host = test_host(vec![(target, STORE_42.to_vec())], vec![spec]);
+        host.prev_state = host.state.clone();
+        host.state.get_mut(&target).unwrap().insert(U256::ZERO, U256::from(7));
+
+        let checkpoint = host.begin_frame();
+        let (ret, _) = call_context(&mut host, spec, "call_prev_state", &[address(target), address(spec), ethabi::Token::Bytes(vec![]), uint(U256::ZERO)]);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&target][&U256::ZERO], U256::from(7));
+        // the enclosing frame fails, the current state keeps its value instead of that of the previous state
+        host.end_frame(checkpoint, false);
+        assert_eq!(host.state[&target][&U256::ZERO], U256::from(7));
+        assert_eq!(host.prev_state[&target][&U256::ZERO], U256::ZERO);
+    }
+
+    #[test]
+    fn test_reverted_transaction_keeps_value_and_bumps_nonce() {
+        let (caller, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let mut host = test_host(vec![(target, STORE_42_AND_REVERT.to_vec())], vec![caller]);
+
+        let (ret, _) = host.execute_transaction(caller, target, Bytes::new(), U256::from(5));
+        assert_eq!(ret, InstructionResult::Revert);
+        assert_eq!(host.set_balance[&caller], U256::from(100));
+        assert_eq!(host.set_balance[&target], U256::from(100));
+        assert_eq!(host.state[&target][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.nonces[&caller], 1);
+    }
+
+    #[test]
+    fn test_failed_create_is_undone() {
+        let (caller, factory) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // PUSH10 <STORE_42_AND_REVERT> PUSH1 0x00 MSTORE
+        // PUSH1 0x0a PUSH1 0x16 PUSH1 0x05 CREATE PUSH1 0x00 SSTORE STOP
+        let mut code = vec![0x69];
+        code.extend_from_slice(&STORE_42_AND_REVERT);
+        code.extend_from_slice(&[0x60, 0x00, 0x52, 0x60, 0x0a, 0x60, 0x16, 0x60, 0x05, 0xf0, 0x60, 0x00, 0x55, 0x00]);
+        let mut host = test_host(vec![(factory, code)], vec![caller]);
+        host.state.get_mut(&factory).unwrap().insert(U256::ZERO, U256::from(7));
+        let created = create_address(factory, 1);
+        add_empty_account(&mut host, created);
+        host.state.get_mut(&created).unwrap().insert(U256::ZERO, U256::ZERO);
+
+        let (ret, _) = call_func(&mut host, caller, factory, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        // CREATE pushed 0
+        assert_eq!(host.state[&factory][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.state[&created][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.set_balance[&factory], U256::from(100));
+        assert_eq!(host.set_balance[&created], U256::ZERO);
+        assert_eq!(host.nonces[&created], 0);
+        assert!(host.codes[&created].is_empty());
+        // the nonce of the creator stays bumped
+        assert_eq!(host.nonces[&factory], 2);
+    }
+
+    #[test]
+    fn test_sstore_reports_original_and_present_value() {
+        let contract = B160::from_low_u64_be(0x1000);
+        let mut host = test_host(vec![(contract, vec![0x00])], vec![]);
+        host.state.get_mut(&contract).unwrap().insert(U256::ZERO, U256::from(7));
+        host.start_transaction(B160::zero(), contract);
+
+        assert_eq!(host.sstore(contract, U256::ZERO, U256::from(8)),
+                   Some((U256::from(7), U256::from(7), U256::from(8), true)));
+        assert_eq!(host.sstore(contract, U256::ZERO, U256::from(9)),
+                   Some((U256::from(7), U256::from(8), U256::from(9), false)));
+
+        host.start_transaction(B160::zero(), contract);
+        assert_eq!(host.sstore(contract, U256::ZERO, U256::from(10)),
+                   Some((U256::from(9), U256::from(9), U256::from(10), true)));
+    }
+
+    #[test]
+    fn test_access_lists_reset_per_transaction() {
+        let (caller, contract, other) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![(contract, vec![0x00])], vec![caller, other]);
+        host.start_transaction(caller, contract);
+
+        assert_eq!(host.load_account(contract), Some((false, false)));
+        assert_eq!(host.load_account(B160::from_low_u64_be(1)), Some((false, false)));
+        assert_eq!(host.balance(other), Some((U256::from(100), true)));
+        assert_eq!(host.balance(other), Some((U256::from(100), false)));
+        assert_eq!(host.sload(contract, U256::ZERO), Some((U256::ZERO, true)));
+        assert_eq!(host.sload(contract, U256::ZERO), Some((U256::ZERO, false)));
+
+        host.start_transaction(caller, contract);
+        assert_eq!(host.load_account(other), Some((true, false)));
+        assert_eq!(host.sload(contract, U256::ZERO), Some((U256::ZERO, true)));
+    }
+
+    #[test]
+    fn test_intrinsic_gas() {
+        assert_eq!(intrinsic_gas(&[]), 21000);
+        assert_eq!(intrinsic_gas(&[0xa9, 0x05, 0x9c, 0xbb, 0x00, 0x00]), 21000 + 4 * 16 + 2 * 4);
+    }
+
+    #[cfg(not(feature = "no_gas_measuring"))]
+    #[test]
+    fn test_call_runs_out_of_gas() {
+        let (caller, contract) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let mut host = test_host(vec![(contract, STORE_42.to_vec())], vec![caller]);
+        host.start_transaction(caller, contract);
+
+        // cold SSTORE of a zero slot costs 22100
+        let (ret, _, _) = call_func_with_gas(&mut host, caller, contract, Bytes::new(), U256::ZERO, 22000);
+        assert_eq!(ret, InstructionResult::OutOfGas);
+
+        host.start_transaction(caller, contract);
+        host.state.get_mut(&contract).unwrap().insert(U256::ZERO, U256::ZERO);
+        let (ret, _, gas) = call_func_with_gas(&mut host, caller, contract, Bytes::new(), U256::ZERO, 30000);
+        assert!(is_call_success(ret));
+        assert_eq!(gas.spend(), 3 + 3 + 22100);
+    }
+
+    #[cfg(not(feature = "no_gas_measuring"))]
+    #[test]
+    fn test_call_forwards_all_but_one_64th() {
+        let (caller, contract, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        // GAS PUSH1 0x00 SSTORE STOP, stores the gas available in the callee
+        let mut host = test_host(vec![
+            (contract, call_code(0xf1, target, Some(0), None)),
+            (target, vec![0x5a, 0x60, 0x00, 0x55, 0x00]),
+        ], vec![caller]);
+        host.start_transaction(caller, contract);
+
+        let (ret, _, _) = call_func_with_gas(&mut host, caller, contract, Bytes::new(), U256::ZERO, 100_000);
+        assert!(is_call_success(ret));
+        // 5 PUSH1 of the arguments, PUSH20 and GAS, then the cold account access of CALL
+        let available = 100_000 - 6 * 3 - 2 - 2600;
+        let forwarded = available - available / 64;
+        // minus GAS in the callee
+        assert_eq!(host.state[&target][&U256::ZERO], U256::from(forwarded - 2));
+    }
+    #[test]
+    fn test_push0_depends_on_spec() {
+        let (caller, contract) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // PUSH0 PUSH1 0x00 SSTORE STOP
+        let mut host = test_host(vec![(contract, vec![0x5f, 0x60, 0x00, 0x55, 0x00])], vec![caller]);
+
+        host.spec_id = parse_spec_id("London");
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert_eq!(ret, InstructionResult::NotActivated);
+
+        for chain in ["ETH", "BSC", "POLYGON", "ARBITRUM"] {
+            host.spec_id = get_spec_id(chain, None);
+            let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+            assert!(is_call_success(ret), "{}", chain);
+        }
+    }
+
+    #[test]
+    fn test_spec_of_forked_block() {
+        assert_eq!(get_spec_id("ETH", Some((17034869, 1681338443))), SpecId::MERGE);
+        assert_eq!(get_spec_id("ETH", Some((17034870, 1681338455))), SpecId::SHANGHAI);
+        assert_eq!(get_spec_id("ETH", Some((19426587, 1710338135))), SpecId::SHANGHAI);
+        assert_eq!(get_spec_id("ETH", Some((12965000, 1628166822))), SpecId::LONDON);
+        assert_eq!(get_spec_id("ETH", Some((1, 1438269988))), SpecId::FRONTIER);
+        assert_eq!(get_spec_id("BSC", Some((31302047, 1681265082))), SpecId::ISTANBUL);
+        assert_eq!(get_spec_id("BSC", Some((35490443, 1705996797))), SpecId::LONDON);
+        assert_eq!(get_spec_id("BSC", Some((35490444, 1705996800))), SpecId::SHANGHAI);
+        assert_eq!(get_spec_id("POLYGON", Some((50522999, 1702351040))), SpecId::LONDON);
+
+        // the block env chooses the spec, unless it is fixed with --spec
+        let mut host = test_host(vec![], vec![]);
+        let block = json!({
+            "number": "0x103ee75", "timestamp": "0x6436c8eb", "miner": "0x000000000000000000000000000000000000c0de",
+            "gasLimit": "0x1c9c380", "difficulty": "0x0",
+        });
+        host.set_block_env(&block);
+        assert_eq!(host.spec_id, SpecId::MERGE);
+        host.spec_id = parse_spec_id("Berlin");
+        host.fixed_spec = true;
+        host.set_block_env(&block);
+        assert_eq!(host.spec_id, SpecId::BERLIN);
+    }
+
+    // call a Context function from `caller`, as a spec does
+    fn call_context(host: &mut TestHost, caller: B160, function: &str, args: &[ethabi::Token]) -> (InstructionResult, Bytes) {
+        let ctx = B160::from_str("8891e33ba3c6A7b4E020A6180Eb07f4AED2d70CE").unwrap();
+        let input = host.context_abi.function(function).unwrap().encode_input(args).unwrap();
+        let (ret, _, out) = host.call(&mut CallInputs {
+            contract: ctx,
+            transfer: Transfer { source: caller, target: ctx, value: U256::ZERO },
+            input: Bytes::from(input),
+            gas_limit: 1_000_000,
+            context: CallContext { address: ctx, caller, code_address: ctx, apparent_value: U256::ZERO, scheme: CallScheme::Call },
+            is_static: false,
+        });
+        (ret, out)
+    }
+
+    fn uint(value: U256) -> ethabi::Token {
+        ethabi::Token::Uint(ethabi::Uint::from_big_endian(&value.to_be_bytes::<32>()))
+    }
+
+    #[test]
+    fn test_call_tree_of_nested_calls() {
+        let (caller, spec, a, b, c, d) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002),
+            B160::from_low_u64_be(0x1003), B160::from_low_u64_be(0x1004), B160::from_low_u64_be(0x1005),
+        );
+        // a: CALL b with 5 wei, then STATICCALL d
+        let mut code = call_code(0xf1, b, Some(5), None);
+        code.pop();
+        code.extend(call_code(0xfa, d, None, None));
+        let mut host = test_host(vec![
+            (a, code),
+            (b, call_code(0xf4, c, None, None)),
+            (c, vec![0x00]),
+            // PUSH1 0x00 PUSH1 0x00 REVERT
+            (d, vec![0x60, 0x00, 0x60, 0x00, 0xfd]),
+        ], vec![caller, spec]);
+
+        let (ret, _) = host.execute_transaction(caller, a, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        let (ret, out) = call_context(&mut host, spec, "get_call_tree", &[]);
+        assert_eq!(ret, InstructionResult::Continue);
+        let tree = host.context_abi.function("get_call_tree").unwrap().decode_output(&out).unwrap();
+        let column = |index: usize| tree[index].clone().into_array().unwrap();
+        let addresses = |index: usize| column(index).into_iter().map(|token| B160::from(token.into_address().unwrap().0)).collect::<Vec<_>>();
+        let uints = |index: usize| column(index).into_iter().map(|token| token.into_uint().unwrap().as_u64()).collect::<Vec<_>>();
+
+        // pre-order: the transaction, a -> b -> c, then a -> d
+        assert_eq!(addresses(0), vec![caller, a, b, a]);
+        assert_eq!(addresses(1), vec![a, b, c, d]);
+        assert_eq!(uints(2), [CallScheme::Call, CallScheme::Call, CallScheme::DelegateCall, CallScheme::StaticCall].map(|scheme| scheme_to_u8(scheme) as u64));
+        assert_eq!(uints(3), vec![0, 5, 0, 0]);
+        assert_eq!(uints(4), vec![0, 1, 2, 1]);
+        let success: Vec<bool> = column(5).into_iter().map(|token| token.into_bool().unwrap()).collect();
+        assert_eq!(success, vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn test_calls_are_recorded_with_their_sender_as_origin() {
+        let (spec, target, first, second) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002), B160::from_low_u64_be(0x1003),
+        );
+        let mut host = test_host(vec![(target, vec![0x00])], vec![spec, first, second]);
+        let origins = |host: &TestHost| host.steps.iter().map(|step| match step {
+            Step::Call { origin, caller, .. } => (*origin, *caller),
+            _ => panic!("unexpected step"),
+        }).collect::<Vec<_>>();
+
+        // a test calling test_call, the transaction origin of the test is not the pranked sender
+        let (ret, _) = call_context(&mut host, spec, "test_call", &[address(target), address(first), ethabi::Token::Bytes(vec![]), uint(U256::ZERO)]);
+        assert!(is_call_success(ret));
+        assert_eq!(origins(&host), vec![(first, first)]);
+        assert_eq!(host.env.tx.caller, B160::zero());
+
+        // fuzzed transactions of different senders
+        host.steps.clear();
+        host.execute_transaction(second, target, Bytes::new(), U256::ZERO);
+        host.execute_transaction(first, target, Bytes::new(), U256::ZERO);
+        assert_eq!(origins(&host), vec![(second, second), (first, first)]);
+    }
+
+    fn abi_call(name: &str, params: &[ethabi::ParamType], args: &[ethabi::Token]) -> Bytes {
+        Bytes::from([ethabi::short_signature(name, params).to_vec(), ethabi::encode(args)].concat())
+    }
+
+    fn address(address: B160) -> ethabi::Token {
+        ethabi::Token::Address(ethabi::Address::from_slice(&address.0))
+    }
+
+    #[test]
+    fn test_erc20_calls_affect_balances_and_allowances() {
+        use ethabi::ParamType::{Address, FixedBytes, Uint};
+        let (caller, spec, token, owner, spender) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002),
+            B160::from_low_u64_be(0x1003), B160::from_low_u64_be(0x1004),
+        );
+        let mut host = test_host(vec![(token, vec![0x00])], vec![caller, spec]);
+
+        let data = abi_call("transferFrom", &[Address, Address, Uint(256)], &[address(owner), address(spender), uint(U256::from(1))]);
+        host.execute_transaction(caller, token, data, U256::ZERO);
+        assert_eq!(host.erc20_affected, HashSet::from([(owner, token), (spender, token)]));
+        assert!(host.erc20_allowances_affected.is_empty());
+
+        let data = abi_call("permit", &[Address, Address, Uint(256), Uint(256), Uint(8), FixedBytes(32), FixedBytes(32)], &[
+            address(owner), address(spender), uint(U256::from(1)), uint(U256::MAX), uint(U256::from(27)),
+            ethabi::Token::FixedBytes(vec![1; 32]), ethabi::Token::FixedBytes(vec![2; 32]),
+        ]);
+        host.execute_transaction(caller, token, data, U256::ZERO);
+        assert_eq!(host.erc20_affected, HashSet::from([(owner, token)]));
+        assert_eq!(host.erc20_allowances_affected, HashSet::from([(owner, spender, token)]));
+        let (_, out) = call_context(&mut host, spec, "get_affected_allowances_ierc20", &[]);
+        let allowances = host.context_abi.function("get_affected_allowances_ierc20").unwrap().decode_output(&out).unwrap();
+        assert_eq!(allowances, vec![
+            ethabi::Token::Array(vec![address(owner)]),
+            ethabi::Token::Array(vec![address(spender)]),
+            ethabi::Token::Array(vec![address(token)]),
+        ]);
+
+        // WETH
+        host.execute_transaction(caller, token, abi_call("deposit", &[], &[]), U256::from(5));
+        assert_eq!(host.erc20_affected, HashSet::from([(caller, token)]));
+        host.execute_transaction(caller, token, abi_call("withdraw", &[Uint(256)], &[uint(U256::from(5))]), U256::ZERO);
+        assert_eq!(host.erc20_affected, HashSet::from([(caller, token)]));
+    }
+
+    #[test]
+    fn test_erc20_logs_affect_balances() {
+        let (token, operator, from, to) = (
+            B160::from_low_u64_be(0x1002), B160::from_low_u64_be(0x1003),
+            B160::from_low_u64_be(0x1004), B160::from_low_u64_be(0x1005),
+        );
+        let mut host = test_host(vec![], vec![]);
+        // Sent(address indexed operator, address indexed from, address indexed to, uint256 amount, bytes data, bytes operatorData)
+        let sent = keccak256(b"Sent(address,address,address,uint256,bytes,bytes)");
+        host.handle_erc20_log(token, &[sent, address_topic(operator), address_topic(from), address_topic(to)]);
+        assert_eq!(host.erc20_affected, HashSet::from([(from, token), (to, token)]));
+
+        host.erc20_affected.clear();
+        host.handle_erc20_log(token, &[keccak256(b"Deposit(address,uint256)"), address_topic(to)]);
+        host.handle_erc20_log(token, &[keccak256(b"Withdrawal(address,uint256)"), address_topic(from)]);
+        assert_eq!(host.erc20_affected, HashSet::from([(from, token), (to, token)]));
+
+        // minted: the zero address is skipped
+        host.erc20_affected.clear();
+        host.handle_erc20_log(token, &[keccak256(b"Transfer(address,address,uint256)"), B256::zero(), address_topic(to)]);
+        assert_eq!(host.erc20_affected, HashSet::from([(to, token)]));
+    }
+
+    #[test]
+    fn test_erc721_and_erc20_transfers_by_topic_count() {
+        let (spec, token, from, to) = (
+            B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002),
+            B160::from_low_u64_be(0x1003), B160::from_low_u64_be(0x1004),
+        );
+        let mut host = test_host(vec![], vec![spec]);
+        host.inside_contract_call = true;
+        let transfer = keccak256(b"Transfer(address,address,uint256)");
+        // ERC20: the amount is in the data
+        host.log(token, vec![transfer, address_topic(from), address_topic(to)], Bytes::from(words(&[U256::from(100)])));
+        assert_eq!(host.erc20_affected, HashSet::from([(from, token), (to, token)]));
+        assert!(host.erc721_affected.is_empty());
+
+        // ERC721: the token id is indexed
+        host.erc20_affected.clear();
+        host.log(token, vec![transfer, address_topic(from), address_topic(to), B256::from(U256::from(7))], Bytes::new());
+        assert!(host.erc20_affected.is_empty());
+        assert_eq!(host.erc721_affected, HashSet::from([(token, from, to, U256::from(7), U256::from(1))]));
+
+        let (_, out) = call_context(&mut host, spec, "get_affected_erc721", &[]);
+        let transfers = host.context_abi.function("get_affected_erc721").unwrap().decode_output(&out).unwrap();
+        assert_eq!(transfers, vec![
+            ethabi::Token::Array(vec![address(token)]),
+            ethabi::Token::Array(vec![address(from)]),
+            ethabi::Token::Array(vec![address(to)]),
+            ethabi::Token::Array(vec![uint(U256::from(7))]),
+            ethabi::Token::Array(vec![uint(U256::from(1))]),
+        ]);
+    }
+
+    #[test]
+    fn test_erc1155_batch_transfers() {
+        use ethabi::ParamType::{Address, Array, Bytes as BytesParam, Uint};
+        let (caller, token, operator, from, to) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1002), B160::from_low_u64_be(0x1003),
+            B160::from_low_u64_be(0x1004), B160::from_low_u64_be(0x1005),
+        );
+        let mut host = test_host(vec![(token, vec![0x00])], vec![caller]);
+        let ids = ethabi::Token::Array(vec![uint(U256::from(1)), uint(U256::from(2))]);
+        let amounts = ethabi::Token::Array(vec![uint(U256::from(10)), uint(U256::from(20))]);
+        let expected = HashSet::from([
+            (token, from, to, U256::from(1), U256::from(10)),
+            (token, from, to, U256::from(2), U256::from(20)),
+        ]);
+
+        // TransferBatch(address indexed operator, address indexed from, address indexed to, uint256[] ids, uint256[] values)
+        host.inside_contract_call = true;
+        let topics = vec![keccak256(b"TransferBatch(address,address,address,uint256[],uint256[])"), address_topic(operator), address_topic(from), address_topic(to)];
+        host.log(token, topics, Bytes::from(ethabi::encode(&[ids.clone(), amounts.clone()])));
+        assert_eq!(host.erc1155_affected, expected);
+        host.inside_contract_call = false;
+
+        let uints = Array(Box::new(Uint(256)));
+        let data = abi_call("safeBatchTransferFrom", &[Address, Address, uints.clone(), uints, BytesParam], &[
+            address(from), address(to), ids, amounts, ethabi::Token::Bytes(vec![]),
+        ]);
+        host.execute_transaction(caller, token, data, U256::ZERO);
+        assert_eq!(host.erc1155_affected, expected);
+        assert!(host.erc721_affected.is_empty());
+    }
+
+    #[test]
+    fn test_prefetch_requests_missing_state_of_access_list() {
+        let (caller, target, token) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![(target, vec![0x00])], vec![caller]);
+        host.state.insert(token, HashMap::from([(U256::from(3), U256::from(9))]));
+        let access_list = json!([
+            {"address": to_str(target), "storageKeys": [slot_to_str(U256::ZERO)]},
+            {"address": to_str(token), "storageKeys": [slot_to_str(U256::from(3)), slot_to_str(U256::from(4))]},
+        ]);
+
+        let (requests, fetched) = host.prefetch_requests(caller, target, &access_list);
+        // the caller, the target and its slots are known, so is slot 3 of the token
+        assert_eq!(fetched, vec![
+            (token, None, "eth_getCode"),
+            (token, None, "eth_getBalance"),
+            (token, None, "eth_getTransactionCount"),
+            (token, Some(U256::from(4)), "eth_getStorageAt"),
+        ]);
+        assert_eq!(requests.len(), fetched.len());
+        assert_eq!(requests[3], ("eth_getStorageAt", json!([to_str(token), slot_to_str(U256::from(4)), rpc::fork_block()])));
+
+        // without an access list only the caller and target are fetched
+        let (_, fetched) = host.prefetch_requests(token, target, &json!(null));
+        assert_eq!(fetched, vec![(token, None, "eth_getCode"), (token, None, "eth_getBalance"), (token, None, "eth_getTransactionCount")]);
+    }
+
+    #[test]
+    fn test_comparison_assertions() {
+        let spec = B160::from_low_u64_be(0x1002);
+        let mut host = test_host(vec![], vec![spec]);
+        let message = || ethabi::Token::String("message".to_string());
+        for (function, a, b, passes) in [
+            ("assert_eq", 5, 5, true), ("assert_eq", 5, 6, false),
+            ("assert_le", 5, 6, true), ("assert_le", 6, 6, true), ("assert_le", 7, 6, false),
+            ("assert_ge", 7, 6, true), ("assert_ge", 6, 6, true), ("assert_ge", 5, 6, false),
+        ] {
+            host.assertion_failures.clear();
+            let (ret, _) = call_context(&mut host, spec, function, &[uint(U256::from(a)), uint(U256::from(b)), message()]);
+            assert_eq!(ret, InstructionResult::Continue);
+            assert_eq!(host.assertion_failures.is_empty(), passes, "{}({}, {})", function, a, b);
+        }
+        let failure = &host.assertion_failures[0];
+        assert_eq!((failure.assertion.as_str(), failure.expected.as_str(), failure.actual.as_str()), ("assert_ge", ">= 6", "5"));
+        assert_eq!((failure.message.as_str(), failure.contract), ("message", spec));
+
+        for (condition, passes) in [(true, true), (false, false)] {
+            host.assertion_failures.clear();
+            call_context(&mut host, spec, "assert_true", &[ethabi::Token::Bool(condition), message()]);
+            assert_eq!(host.assertion_failures.is_empty(), passes);
+        }
+        assert_eq!(host.assertion_failures[0].assertion, "assert_true");
+    }
+
+    #[test]
+    fn test_approx_eq_rel() {
+        let percent = U256::from(10_000_000_000_000_000u64);
+        assert!(approx_eq_rel(U256::from(101), U256::from(100), percent));
+        assert!(approx_eq_rel(U256::from(99), U256::from(100), percent));
+        assert!(!approx_eq_rel(U256::from(102), U256::from(100), percent));
+        // against zero only an exact match passes
+        assert!(approx_eq_rel(U256::ZERO, U256::ZERO, U256::ZERO));
+        assert!(!approx_eq_rel(U256::from(1), U256::ZERO, U256::MAX));
+        // delta * 1e18 does not fit in 256 bits
+        let half = U256::MAX / U256::from(2);
+        assert!(approx_eq_rel(U256::MAX, half, U256::from(2) * percent * U256::from(100)));
+        assert!(!approx_eq_rel(U256::MAX, half, percent * U256::from(99)));
+        assert!(approx_eq_rel(U256::MAX, U256::MAX - U256::from(1), U256::from(1)));
+
+        let spec = B160::from_low_u64_be(0x1002);
+        let mut host = test_host(vec![], vec![spec]);
+        let message = ethabi::Token::String("message".to_string());
+        call_context(&mut host, spec, "assert_approx_eq_rel", &[uint(U256::MAX), uint(half), uint(percent), message.clone()]);
+        assert_eq!(host.assertion_failures.len(), 1);
+        assert_eq!(host.assertion_failures[0].expected, format!("{} (max relative delta {} / 1e18)", half, percent));
+        call_context(&mut host, spec, "assert_approx_eq_rel", &[uint(U256::from(101)), uint(U256::from(100)), uint(percent), message]);
+        assert_eq!(host.assertion_failures.len(), 1);
+    }
+
+    fn call_precompile(host: &mut TestHost, index: u64, input: &[u8]) -> (InstructionResult, Bytes) {
+        let (caller, address) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(index));
+        let (ret, _, out) = host.call(&mut CallInputs {
+            contract: address,
+            transfer: Transfer { source: caller, target: address, value: U256::ZERO },
+            input: Bytes::from(input.to_vec()),
+            gas_limit: 1_000_000,
+            context: CallContext { address, caller, code_address: address, apparent_value: U256::ZERO, scheme: CallScheme::Call },
+            is_static: false,
+        });
+        (ret, out)
+    }
+
+    #[test]
+    fn test_precompile_vectors() {
+        let mut host = test_host(vec![], vec![B160::from_low_u64_be(0x1000)]);
+        // ecrecover
+        let input = hex::decode(concat!(
+            "38d18acb67d25c8bb9942764b62f18e17054f66a817bd4295423adf9ed98873e",
+            "000000000000000000000000000000000000000000000000000000000000001b",
+            "38d18acb67d25c8bb9942764b62f18e17054f66a817bd4295423adf9ed98873e",
+            "789d1dd423d25f0772d2748d60f7e4b81bb14d086eba8e8e8efb6dcff8a4ae02",
+        )).unwrap();
+        let (ret, out) = call_precompile(&mut host, 1, &input);
+        assert_eq!(ret, InstructionResult::Return);
+        assert_eq!(hex::encode(out), "000000000000000000000000ceaccac640adf55b2028469bd36ba501f28b699d");
+        // sha256
+        let (ret, out) = call_precompile(&mut host, 2, b"abc");
+        assert_eq!(ret, InstructionResult::Return);
+        assert_eq!(hex::encode(out), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // ripemd160, left padded to a word
+        let (ret, out) = call_precompile(&mut host, 3, b"abc");
+        assert_eq!(ret, InstructionResult::Return);
+        assert_eq!(hex::encode(out), "0000000000000000000000008eb208f7e05d987a9b044a8e98c6b087f15a0bfc");
+        // identity
+        let (ret, out) = call_precompile(&mut host, 4, b"hello");
+        assert_eq!(ret, InstructionResult::Return);
+        assert_eq!(out, Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn test_precompiles_depend_on_spec() {
+        let mut host = test_host(vec![], vec![B160::from_low_u64_be(0x1000)]);
+        add_empty_account(&mut host, B160::from_low_u64_be(9));
+        // blake2f only takes 213 byte inputs
+        host.spec_id = parse_spec_id("Istanbul");
+        let (ret, _) = call_precompile(&mut host, 9, b"abc");
+        assert_eq!(ret, InstructionResult::PrecompileError);
+
+        // before Istanbul 0x09 is an empty account
+        host.spec_id = parse_spec_id("Petersburg");
+        let (ret, out) = call_precompile(&mut host, 9, b"abc");
+        assert!(is_call_success(ret));
+        assert!(out.is_empty());
+        assert!(!host.precompiles().contains(&B160::from_low_u64_be(9).0));
+    }
+
+    // account that is known to not exist on the forked chain
+    fn add_empty_account(host: &mut TestHost, address: B160) {
+        host.codes.insert(address, Bytecode::new());
+        host.nonces.insert(address, 0);
+        host.set_balance.insert(address, U256::ZERO);
+        host.state.insert(address, HashMap::new());
+    }
+
+    #[test]
+    fn test_load_account_reports_empty_accounts() {
+        let (caller, contract, empty) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![(contract, vec![0x00])], vec![caller]);
+        add_empty_account(&mut host, empty);
+        host.start_transaction(caller, contract);
+
+        assert_eq!(host.load_account(contract), Some((false, false)));
+        assert_eq!(host.load_account(caller), Some((false, false)));
+        assert_eq!(host.load_account(empty), Some((true, true)));
+        assert_eq!(host.code_hash(empty), Some((B256::zero(), false)));
+        assert_eq!(host.code_hash(contract), Some((keccak256(&[0x00]), false)));
+
+        host.nonces.insert(empty, 1);
+        assert_eq!(host.code_hash(empty), Some((KECCAK_EMPTY, false)));
+    }
+
+    #[test]
+    fn test_create_derives_address_from_nonce() {
+        let (caller, factory) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // PUSH5 <PUSH1 0x01 PUSH1 0x00 RETURN> PUSH1 0x00 MSTORE
+        // PUSH1 0x05 PUSH1 0x1b PUSH1 0x00 CREATE PUSH1 0x00 SSTORE STOP
+        let mut host = test_host(vec![(factory, vec![
+            0x64, 0x60, 0x01, 0x60, 0x00, 0xf3, 0x60, 0x00, 0x52,
+            0x60, 0x05, 0x60, 0x1b, 0x60, 0x00, 0xf0, 0x60, 0x00, 0x55, 0x00,
+        ])], vec![caller]);
+        let (first, second) = (create_address(factory, 1), create_address(factory, 2));
+        add_empty_account(&mut host, first);
+        add_empty_account(&mut host, second);
+
+        for created in [first, second] {
+            let (ret, _) = call_func(&mut host, caller, factory, Bytes::new(), U256::ZERO);
+            assert!(is_call_success(ret));
+            assert_eq!(host.state[&factory][&U256::ZERO], U256::from_be_slice(&created.0));
+            assert_eq!(host.nonces[&created], 1);
+            assert_eq!(host.codes[&created].original_bytes(), Bytes::from(vec![0x00]));
+        }
+        assert_eq!(host.nonces[&factory], 3);
+    }
+    #[test]
+    fn test_call_fails_when_sender_cannot_pay() {
+        let (caller, contract, target) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let mut host = test_host(vec![
+            (contract, call_code(0xf1, target, Some(200), Some(1))),
+            (target, STORE_42.to_vec()),
+        ], vec![caller]);
+        host.state.get_mut(&contract).unwrap().insert(U256::from(1), U256::from(7));
+        host.inside_contract_call = true;
+
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        // CALL pushed 0
+        assert_eq!(host.state[&contract][&U256::from(1)], U256::ZERO);
+        assert_eq!(host.state[&target][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.set_balance[&contract], U256::from(100));
+        assert_eq!(host.set_balance[&target], U256::from(100));
+        assert_eq!(host.call_tree.len(), 1);
+        assert!(!host.call_tree[0].success);
+    }
+
+    #[test]
+    fn test_create_fails_when_sender_cannot_pay() {
+        let (caller, factory) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // PUSH1 0x00 PUSH1 0x00 PUSH1 0xc8 CREATE PUSH1 0x00 SSTORE STOP
+        let mut host = test_host(vec![(factory, vec![
+            0x60, 0x00, 0x60, 0x00, 0x60, 0xc8, 0xf0, 0x60, 0x00, 0x55, 0x00,
+        ])], vec![caller]);
+        host.state.get_mut(&factory).unwrap().insert(U256::ZERO, U256::from(7));
+
+        let (ret, _) = call_func(&mut host, caller, factory, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&factory][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.nonces[&factory], 1);
+        assert_eq!(host.set_balance[&factory], U256::from(100));
+    }
+
+    fn words(values: &[U256]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_be_bytes::<32>()).collect()
+    }
+
+    fn address_topic(address: B160) -> B256 {
+        let mut topic = B256::zero();
+        topic.0[12..].copy_from_slice(&address.0);
+        topic
+    }
+
+    #[test]
+    fn test_uniswap_v3_swap_log_signed_amounts() {
+        let pool = B160::from_low_u64_be(0x2001);
+        let topics = vec![
+            keccak256(b"Swap(address,address,int256,int256,uint160,uint128,int24)"),
+            address_topic(B160::from_low_u64_be(1)), address_topic(B160::from_low_u64_be(2)),
+        ];
+        let rest = [U256::from(1) << 96, U256::from(10).pow(U256::from(18)), U256::ZERO];
+        // the pool receives 500 of token1 and sends 1000 of token0
+        let data = words(&[[U256::from(1000).wrapping_neg(), U256::from(500)].as_slice(), &rest].concat());
+        let swap = swap_from_log(pool, &topics, &data).unwrap();
+        assert_eq!((swap.pool, swap.kind), (pool, SwapKind::UniswapV3));
+        assert_eq!((swap.token_in, swap.token_out), (SwapToken::Token1, SwapToken::Token0));
+        assert_eq!((swap.amount_in, swap.amount_out), (U256::from(500), U256::from(1000)));
+
+        let data = words(&[[U256::from(700), U256::from(300).wrapping_neg()].as_slice(), &rest].concat());
+        let swap = swap_from_log(pool, &topics, &data).unwrap();
+        assert_eq!((swap.token_in, swap.token_out), (SwapToken::Token0, SwapToken::Token1));
+        assert_eq!((swap.amount_in, swap.amount_out), (U256::from(700), U256::from(300)));
+    }
+
+    #[test]
+    fn test_curve_token_exchange_log() {
+        let pool = B160::from_low_u64_be(0x2002);
+        let buyer = address_topic(B160::from_low_u64_be(1));
+        let data = words(&[U256::from(1), U256::from(100), U256::ZERO, U256::from(99)]);
+
+        let topics = vec![keccak256(b"TokenExchange(address,int128,uint256,int128,uint256)"), buyer];
+        let swap = swap_from_log(pool, &topics, &data).unwrap();
+        assert_eq!((swap.pool, swap.kind), (pool, SwapKind::Curve));
+        assert_eq!((swap.token_in, swap.token_out), (SwapToken::Coin(U256::from(1)), SwapToken::Coin(U256::ZERO)));
+        assert_eq!((swap.amount_in, swap.amount_out), (U256::from(100), U256::from(99)));
+
+        let topics = vec![keccak256(b"TokenExchange(address,uint256,uint256,uint256,uint256)"), buyer];
+        assert_eq!(swap_from_log(pool, &topics, &data).unwrap().token_in, SwapToken::Coin(U256::from(1)));
+
+        let topics = vec![keccak256(b"TokenExchangeUnderlying(address,int128,uint256,int128,uint256)"), buyer];
+        let swap = swap_from_log(pool, &topics, &data).unwrap();
+        assert_eq!((swap.token_in, swap.token_out), (SwapToken::UnderlyingCoin(U256::from(1)), SwapToken::UnderlyingCoin(U256::ZERO)));
+
+        // truncated data is not a swap
+        assert!(swap_from_log(pool, &topics, &data[..96]).is_none());
+    }
+
+    #[test]
+    fn test_balancer_swap_log() {
+        let (vault, pool, token_in, token_out) = (
+            B160::from_low_u64_be(0x2003), B160::from_low_u64_be(0x2004),
+            B160::from_low_u64_be(0x3001), B160::from_low_u64_be(0x3002),
+        );
+        // pool id: pool address, specialization and nonce
+        let mut pool_id = B256::zero();
+        pool_id.0[..20].copy_from_slice(&pool.0);
+        pool_id.0[31] = 0x5c;
+        let topics = vec![keccak256(b"Swap(bytes32,address,address,uint256,uint256)"), pool_id, address_topic(token_in), address_topic(token_out)];
+        let swap = swap_from_log(vault, &topics, &words(&[U256::from(10), U256::from(20)])).unwrap();
+        assert_eq!((swap.pool, swap.kind), (pool, SwapKind::Balancer));
+        assert_eq!((swap.token_in, swap.token_out), (SwapToken::Address(token_in), SwapToken::Address(token_out)));
+        assert_eq!((swap.amount_in, swap.amount_out), (U256::from(10), U256::from(20)));
+        // tokens are indexed
+        assert!(swap_from_log(vault, &topics[..3], &words(&[U256::from(10), U256::from(20)])).is_none());
+    }
+
+    #[test]
+    fn test_swap_tokens_are_read_without_warming_the_pool() {
+        let (pool, token) = (B160::from_low_u64_be(0x2005), B160::from_low_u64_be(0x3003));
+        // PUSH20 token PUSH1 0x00 MSTORE PUSH1 0x20 PUSH1 0x00 RETURN
+        let mut code = vec![0x73];
+        code.extend_from_slice(&token.0);
+        code.extend_from_slice(&[0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3]);
+        let mut host = test_host(vec![(pool, code)], vec![]);
+        let topics = vec![keccak256(b"Swap(address,uint256,uint256,uint256,uint256,address)")];
+        host.handle_swap_log(pool, &topics, &words(&[U256::from(5), U256::ZERO, U256::ZERO, U256::from(4)]));
+        assert_eq!(host.swaps_affected[0].token_in, SwapToken::Token0);
+        assert!(host.pairs_affected.contains(&pool));
+
+        host.start_transaction(B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        assert_eq!(host.swap_token(pool, SwapToken::Token0), token);
+        assert!(!host.accessed_accounts.contains(&pool));
+        assert_eq!(host.pool_tokens[&(pool, [0x0d, 0xfe, 0x16, 0x81], U256::ZERO)], token);
+    }
+
+    // contract and account served by the mock server from fixtures/host.json,
+    // the contract returns slot 0 (0x2a) and slot 1 is 7
+    fn forked_host() -> (TestHost, B160, B160) {
+        mock_server::shared();
+        let (context_info, context_abi) = load_context_abi();
+        let host = TestHost::new(context_info, context_abi, "ETH".to_string());
+        (host, B160::from_low_u64_be(0xca11), B160::from_low_u64_be(0xf00d))
+    }
+
+    #[test]
+    fn test_prefetch_fetches_accessed_state() {
+        let (mut host, caller, contract) = forked_host();
+
+        let (ret, out) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(U256::from_be_bytes::<32>(out.as_ref().try_into().unwrap()), U256::from(42));
+        // everything in the access list was fetched in one go
+        assert_eq!(host.state[&contract].len(), 1);
+        assert_eq!(host.nonces[&caller], 5);
+        assert_eq!(host.nonces[&contract], 1);
+        assert_eq!(host.set_balance[&caller], U256::from(1_000_000_000_000_000_000u64));
+    }
+
+    #[test]
+    fn test_failed_request_aborts_only_the_transaction() {
+        let (mut host, caller, contract) = forked_host();
+        host.prefetch = false;
+        // stores 1 in slot 0, then sends 1 wei to an account the node knows nothing about
+        let unknown = B160::from_low_u64_be(0xdead);
+        let sender = B160::from_low_u64_be(0x5e4d);
+        let mut code = vec![0x60, 0x01, 0x60, 0x00, 0x55];
+        code.extend(call_code(0xf1, unknown, Some(1), None));
+        host.codes.insert(sender, to_analysed(Bytecode::new_raw(Bytes::from(code))));
+        host.state.insert(sender, HashMap::from([(U256::ZERO, U256::ZERO)]));
+        host.set_balance.insert(sender, U256::from(100));
+        host.nonces.insert(sender, 1);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            host.start_transaction(caller, sender);
+            call_func(&mut host, caller, sender, Bytes::new(), U256::ZERO)
+        }));
+        assert!(result.is_err());
+        assert_eq!(host.state[&sender][&U256::ZERO], U256::from(1));
+        host.abort_transaction();
+        assert_eq!(host.state[&sender][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.set_balance[&sender], U256::from(100));
+        assert_eq!((host.frames, host.call_depth, host.inside_contract_call), (0, 0, false));
+
+        // the next transaction runs as usual
+        let (ret, out) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(U256::from_be_bytes::<32>(out.as_ref().try_into().unwrap()), U256::from(42));
+    }
+
+    #[test]
+    fn test_dropped_pending_filter_is_recreated() {
+        mock_server::shared();
+        // the node knows nothing of filter 0x1, it creates filter 0x2
+        let mut filter = Some(json!("0x1"));
+        let mut seen = HashSet::new();
+        assert!(poll_pending_transactions(&mut filter, &mut seen).unwrap().is_empty());
+        assert_eq!(filter, Some(json!("0x2")));
+        assert!(poll_pending_transactions(&mut filter, &mut seen).unwrap().is_empty());
+        assert_eq!(filter, Some(json!("0x2")));
+    }
+
+    #[test]
+    fn test_pending_block_env() {
+        let mut host = test_host(vec![], vec![]);
+        let block = json!({
+            "number": "0x10", "timestamp": "0x64", "miner": "0x000000000000000000000000000000000000c0de",
+            "gasLimit": "0x1c9c380", "difficulty": "0x0",
+        });
+        host.set_pending_block_env(&block);
+        assert_eq!(host.env.block.number, U256::from(0x11));
+        assert!(host.env.block.timestamp > U256::from(0x64));
+
+        // blocks timestamped ahead of the local clock
+        let future = U256::from(u64::MAX / 2);
+        host.set_pending_block_env(&json!({
+            "number": "0x10", "timestamp": format!("{:#x}", future), "miner": "0x000000000000000000000000000000000000c0de",
+            "gasLimit": "0x1c9c380", "difficulty": "0x0",
+        }));
+        assert_eq!(host.env.block.timestamp, future + U256::from(1));
+    }
+
+    #[test]
+    fn test_fetches_missing_slots() {
+        let (mut host, _, contract) = forked_host();
+        host.prefetch = false;
+
+        assert_eq!(host.sload(contract, U256::from(1)), Some((U256::from(7), true)));
+        assert_eq!(host.sload(contract, U256::from(1)), Some((U256::from(7), false)));
+        assert_eq!(host.state[&contract].len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "offline mode")]
+    fn test_offline_host_does_not_fetch() {
+        let (mut host, caller, contract) = forked_host();
+        host.offline = true;
+        call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+    }
+
+    #[test]
+    fn test_dump_and_load_state() {
+        let (caller, contract) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let mut host = test_host(vec![(contract, STORE_42.to_vec())], vec![caller]);
+        let (ret, _) = call_func(&mut host, caller, contract, Bytes::new(), U256::ZERO);
+        assert!(is_call_success(ret));
+
+        let state = host.dump_state();
+        assert_eq!(state[to_str(contract)]["storage"][slot_to_str(U256::ZERO)], json!(slot_to_str(U256::from(42))));
+        assert_eq!(state[to_str(contract)]["code"], json!(hex_encode_with_prefix(&STORE_42)));
+        assert_eq!(state[to_str(caller)]["balance"], json!("0x64"));
+
+        let (context_info, context_abi) = load_context_abi();
+        let mut loaded = TestHost::new(context_info, context_abi, "ETH".to_string());
+        loaded.load_state(&state);
+        assert_eq!(loaded.state, host.state);
+        assert_eq!(loaded.set_balance, host.set_balance);
+        assert_eq!(loaded.nonces, host.nonces);
+        assert_eq!(loaded.codes[&contract].original_bytes(), host.codes[&contract].original_bytes());
+        assert!(loaded.codes[&caller].is_empty());
+        assert_eq!(loaded.dump_state(), state);
+    }
+
+    #[test]
+    fn test_load_geth_alloc() {
+        let account = B160::from_low_u64_be(0x1000);
+        let mut host = test_host(vec![], vec![]);
+        host.load_state(&json!({
+            "0x0000000000000000000000000000000000001000": {
+                "balance": "1000000000000000000",
+                "storage": {"0x01": "0x02"},
+            }
+        }));
+        assert_eq!(host.set_balance[&account], U256::from(1_000_000_000_000_000_000u64));
+        assert_eq!(host.nonces[&account], 0);
+        assert!(host.codes[&account].is_empty());
+        assert_eq!(host.state[&account][&U256::from(1)], U256::from(2));
+    }
+
+    #[test]
+    fn test_execute_transaction_and_evaluate_invariants() {
+        let (caller, target, spec) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        // PUSH1 0x07 PUSH1 0x00 SSTORE PUSH1 0x01 PUSH1 0x00 MSTORE PUSH1 0x20 PUSH1 0x00 RETURN
+        let mut host = test_host(vec![
+            (target, STORE_42.to_vec()),
+            (spec, vec![0x60, 0x07, 0x60, 0x00, 0x55, 0x60, 0x01, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3]),
+        ], vec![caller]);
+        host.abis.insert(spec, serde_json::from_value(json!([
+            {"type": "function", "name": "invariant_a", "inputs": [], "outputs": [{"name": "", "type": "bool"}], "stateMutability": "nonpayable"},
+            {"type": "function", "name": "test_a", "inputs": [], "outputs": [], "stateMutability": "nonpayable"},
+        ])).unwrap());
+
+        let (ret, _) = host.execute_transaction(caller, target, Bytes::from(vec![0x12, 0x34, 0x56, 0x78]), U256::ZERO);
+        assert!(is_call_success(ret));
+        assert_eq!(host.state[&target][&U256::ZERO], U256::from(42));
+        assert_eq!(host.prev_state[&target][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.call_tree.len(), 1);
+        assert_eq!((host.call_tree[0].caller, host.call_tree[0].target), (caller, target));
+        assert_eq!((host.caller, host.target), (caller, target));
+        assert_eq!(host.nonces[&caller], 1);
+
+        let outcomes = host.evaluate_invariants(&[spec]);
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].name, "invariant_a");
+        assert!(outcomes[0].fired);
+        // the invariant's own writes are discarded
+        assert_eq!(host.state[&spec][&U256::ZERO], U256::ZERO);
+    }
+
+    #[test]
+    fn test_parse_historical_transaction() {
+        let tx = HistoricalTx::from_json(&json!({
+            "hash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": "0x10",
+            "from": "0x0000000000000000000000000000000000001000",
+            "to": null,
+            "input": "0x6000",
+            "value": "0xde0b6b3a7640000",
+            "gas": "0x5208",
+            "gasPrice": "0x3b9aca00",
+        }));
+        assert_eq!(tx.block, 16);
+        assert_eq!(tx.caller, B160::from_low_u64_be(0x1000));
+        assert_eq!(tx.target, None);
+        assert_eq!(tx.data, Bytes::from(vec![0x60, 0x00]));
+        assert_eq!(tx.value, U256::from(1_000_000_000_000_000_000u64));
+        assert_eq!(tx.gas_limit, 21000);
+    }
+
+    #[test]
+    fn test_watched_transactions() {
+        let (router, pool, other) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001), B160::from_low_u64_be(0x1002));
+        let tx = |hash: u64, target: Option<B160>| HistoricalTx {
+            hash: B256::from_low_u64_be(hash),
+            block: 1,
+            caller: other,
+            target,
+            data: Bytes::new(),
+            value: U256::ZERO,
+            gas_limit: 21000,
+            gas_price: U256::ZERO,
+        };
+        let watch = HashSet::from([pool]);
+        // the pool emitted a log in the second transaction
+        let logged = HashSet::from([B256::from_low_u64_be(2)]);
+        assert!(is_watched(&tx(1, Some(pool)), &watch, &logged));
+        assert!(is_watched(&tx(2, Some(router)), &watch, &logged));
+        assert!(!is_watched(&tx(3, Some(router)), &watch, &logged));
+        assert!(!is_watched(&tx(4, None), &watch, &logged));
+    }
+
+    #[test]
+    fn test_simulate_pending_transaction() {
+        let (caller, target, spec, other) = (
+            B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001),
+            B160::from_low_u64_be(0x1002), B160::from_low_u64_be(0x1003),
+        );
+        // PUSH1 0x01 PUSH1 0x00 MSTORE PUSH1 0x20 PUSH1 0x00 RETURN
+        let mut host = test_host(vec![
+            (target, STORE_42.to_vec()),
+            (spec, vec![0x60, 0x01, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3]),
+        ], vec![caller]);
+        host.abis.insert(spec, serde_json::from_value(json!([
+            {"type": "function", "name": "invariant_a", "inputs": [], "outputs": [{"name": "", "type": "bool"}], "stateMutability": "nonpayable"},
+        ])).unwrap());
+        let tx = HistoricalTx {
+            hash: B256::from_low_u64_be(1),
+            block: 0,
+            caller,
+            target: Some(target),
+            data: Bytes::new(),
+            value: U256::ZERO,
+            gas_limit: 100000,
+            gas_price: U256::ZERO,
+        };
+
+        let snapshot = host.snapshot();
+        assert!(simulate_pending_transaction(&mut host, &tx, &HashSet::from([other]), &[spec]).is_none());
+        host.restore(snapshot);
+        let snapshot = host.snapshot();
+        let outcomes = simulate_pending_transaction(&mut host, &tx, &HashSet::from([target]), &[spec]).unwrap();
+        assert!(outcomes[0].fired);
+        assert_eq!(host.state[&target][&U256::ZERO], U256::from(42));
+        host.restore(snapshot);
+        assert_eq!(host.state[&target][&U256::ZERO], U256::ZERO);
+        assert_eq!(host.nonces[&caller], 0);
+
+        host.retain_accounts(&HashSet::from([spec]));
+        assert!(host.codes.contains_key(&spec) && host.state.contains_key(&spec));
+        assert!(!host.codes.contains_key(&target) && !host.set_balance.contains_key(&caller));
+    }
+
+    // any call data (e.g. set(uint256)) stores 42 in slot 0, empty call data returns slot 0:
+    // CALLDATASIZE ISZERO PUSH1 0x0b JUMPI, then either store 42 or return slot 0
+    const SET_42: [u8; 23] = [
+        0x36, 0x15, 0x60, 0x0b, 0x57, 0x60, 0x2a, 0x60, 0x00, 0x55, 0x00,
+        0x5b, 0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+    ];
+
+    // test host with the target and a spec whose invariant_a fires once the target returns 42 for empty call data
+    fn fuzz_host(target: B160, target_code: Vec<u8>, spec: B160) -> TestHost {
+        // STATICCALL the target and return whether it returned 42
+        let mut spec_code = vec![0x60, 0x20, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x73];
+        spec_code.extend_from_slice(&target.0);
+        spec_code.extend_from_slice(&[
+            0x5a, 0xfa, 0x50, 0x60, 0x00, 0x51, 0x60, 0x2a, 0x14, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+        ]);
+        let mut host = test_host(vec![(target, target_code), (spec, spec_code)], vec![]);
+        host.abis.insert(spec, serde_json::from_value(json!([
+            {"type": "function", "name": "invariant_a", "inputs": [], "outputs": [{"name": "", "type": "bool"}], "stateMutability": "nonpayable"},
+        ])).unwrap());
+        host
+    }
+
+    #[test]
+    fn test_fuzz_finds_sequence() {
+        let (target, spec) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let mut host = fuzz_host(target, SET_42.to_vec(), spec);
+        let abi = serde_json::from_value(json!([
+            {"type": "function", "name": "set", "inputs": [{"name": "x", "type": "uint256"}], "outputs": [], "stateMutability": "nonpayable"},
+            {"type": "function", "name": "get", "inputs": [], "outputs": [{"name": "", "type": "uint256"}], "stateMutability": "view"},
+        ])).unwrap();
+
+        let alerter = Alerter { sinks: vec![], foundry: None, spec_names: HashMap::new() };
+        let mut fuzzer = Fuzzer::new(vec![FuzzTarget { address: target, abi }], &mut host, 2, 4, 0);
+        let findings = fuzzer.fuzz(&mut host, &[spec], 3, &alerter);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].outcome.name, "invariant_a");
+        assert_eq!(findings[0].sequence.len(), 1);
+        assert_eq!(findings[0].sequence[0].function, "set");
+        assert_eq!(host.state[&target][&U256::ZERO], U256::ZERO);
+    }
+
+    #[test]
+    fn test_fuzz_finds_magic_value() {
+        let (target, spec) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // set(uint256) stores 42 in slot 0 if its argument is 0xdeadbeefcafe, empty calldata returns slot 0
+        let target_code = vec![
+            0x36, 0x15, 0x60, 0x1b, 0x57, 0x60, 0x04, 0x35, 0x65, 0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe, 0x14, 0x60, 0x14, 0x57, 0x00,
+            0x5b, 0x60, 0x2a, 0x60, 0x00, 0x55, 0x00,
+            0x5b, 0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+        ];
+        let mut host = fuzz_host(target, target_code, spec);
+        let abi = serde_json::from_value(json!([
+            {"type": "function", "name": "set", "inputs": [{"name": "x", "type": "uint256"}], "outputs": [], "stateMutability": "nonpayable"},
+        ])).unwrap();
+
+        let alerter = Alerter { sinks: vec![], foundry: None, spec_names: HashMap::new() };
+        let mut fuzzer = Fuzzer::new(vec![FuzzTarget { address: target, abi }], &mut host, 2, 4, 0);
+        let findings = fuzzer.fuzz(&mut host, &[spec], 200, &alerter);
+        assert!(fuzzer.dictionary.values.contains(&U256::from(0xdeadbeefcafeu64)));
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].sequence.last().unwrap().args, vec![ethabi::Token::Uint(0xdeadbeefcafeu64.into())]);
+    }
+
+    #[test]
+    fn test_minimize_sequence() {
+        let (target, spec) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        // set(uint256) stores 42 in slot 0 if its argument is at least 1000, empty calldata returns slot 0
+        let target_code = vec![
+            0x36, 0x15, 0x60, 0x17, 0x57, 0x61, 0x03, 0xe8, 0x60, 0x04, 0x35, 0x10, 0x60, 0x15, 0x57,
+            0x60, 0x2a, 0x60, 0x00, 0x55, 0x00, 0x5b, 0x00,
+            0x5b, 0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+        ];
+        let mut host = fuzz_host(target, target_code, spec);
+        let abi: ethabi::Contract = serde_json::from_value(json!([
+            {"type": "function", "name": "set", "inputs": [{"name": "x", "type": "uint256"}], "outputs": [], "stateMutability": "payable"},
+        ])).unwrap();
+        let set = abi.function("set").unwrap().clone();
+        let mut fuzzer = Fuzzer::new(vec![FuzzTarget { address: target, abi }], &mut host, 2, 4, 0);
+        let (first, second) = (fuzzer.senders[0], fuzzer.senders[1]);
+        let call = |sender: B160, x: u64, value: u64| {
+            let args = vec![ethabi::Token::Uint(x.into())];
+            FuzzCall { sender, target, function: "set".to_string(), data: Bytes::from(set.encode_input(&args).unwrap()), args, value: U256::from(value) }
+        };
+
+        let initial = host.snapshot();
+        let sequence = vec![call(second, 5, 0), call(second, 123456789, 1000), call(first, 7, 0), call(first, 8, 0)];
+        let (executed, outcomes, _) = fuzzer.run_sequence(&mut host, &[spec], &HashSet::new(), sequence);
+        assert_eq!(executed.len(), 2);
+        let dictionary = fuzzer.dictionary.values.clone();
+        let (minimized, outcome) = minimize(&fuzzer, &mut host, &initial, &executed, &outcomes[0]);
+        assert!(outcome.fired);
+        // the replays do not feed back into fuzzing
+        assert_eq!(fuzzer.dictionary.values, dictionary);
+        assert!(host.coverage.is_none() && host.cmp_log.is_empty());
+        assert_eq!(minimized.len(), 1);
+        assert_eq!(minimized[0].to_string(), call(first, 1000, 0).to_string());
+        assert_eq!(minimized[0].data, call(first, 1000, 0).data);
+
+        // the host is left after the minimized sequence, which a Foundry test reproduces after funding the senders
+        assert!(matches!(host.steps.as_slice(), [Step::Deal { .. }, Step::Deal { .. }, Step::Call { caller, success: true, .. }] if *caller == first));
+        let solidity = Reproduction::new(&host, "spec", &outcome.name, &outcome.output).to_solidity();
+        assert!(solidity.contains("contract spec_invariant_a is Test {"));
+        assert!(solidity.contains(&format!("        vm.prank({0}, {0});\n", foundry::checksum(first))));
+        assert!(solidity.contains(&format!("address({}).call(hex\"{}\");", foundry::checksum(target), hex::encode(&minimized[0].data))));
+
+        // the sequence does not make the invariant fire again, e.g. as state fetched while fuzzing changed in the meantime,
+        // the sequence that was found is kept
+        let mut changed = initial.clone();
+        changed.codes.insert(target, to_analysed(Bytecode::new_raw(Bytes::from(vec![0x00]))));
+        let (kept, outcome) = minimize(&fuzzer, &mut host, &changed, &executed, &outcomes[0]);
+        assert!(outcome.fired);
+        assert_eq!(kept.iter().map(|call| call.to_string()).collect::<Vec<_>>(), executed.iter().map(|call| call.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_fuzz_corpus_coverage_resumes() {
+        let target = B160::from_low_u64_be(0x1000);
+        let abi: ethabi::Contract = serde_json::from_value(json!([
+            {"type": "function", "name": "set", "inputs": [{"name": "x", "type": "uint256"}], "outputs": [], "stateMutability": "nonpayable"},
+        ])).unwrap();
+        let dir = std::env::temp_dir().join(format!("corpus-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let alerter = Alerter { sinks: vec![], foundry: None, spec_names: HashMap::new() };
+
+        let mut host = test_host(vec![(target, SET_42.to_vec())], vec![]);
+        let mut fuzzer = Fuzzer::new(vec![FuzzTarget { address: target, abi: abi.clone() }], &mut host, 2, 1, 0);
+        fuzzer.load_corpus(&mut host, dir);
+        assert!(fuzzer.corpus.is_empty());
+        fuzzer.fuzz(&mut host, &[], 10, &alerter);
+        // the JUMPI of set() falls through
+        let hash = host.codes[&target].hash();
+        assert_eq!(fuzzer.corpus.coverage, HashSet::from([(hash, 4, 5)]));
+        assert_eq!(fuzzer.corpus.entries.len(), 1);
+
+        // a new run with other senders replays the saved sequence
+        let mut host = test_host(vec![(target, SET_42.to_vec())], vec![]);
+        let mut fuzzer = Fuzzer::new(vec![FuzzTarget { address: target, abi }], &mut host, 1, 2, 1);
+        fuzzer.load_corpus(&mut host, dir);
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(fuzzer.corpus.coverage, HashSet::from([(hash, 4, 5)]));
+        assert_eq!(fuzzer.senders.len(), 2);
+    }
+}
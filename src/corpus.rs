@@ -0,0 +1,114 @@
+// corpus of the call sequences that reached new coverage, mutated by the fuzzer and kept on disk to resume a run
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::rngs::StdRng;
+use revm::primitives::keccak256;
+use serde_json::{json, Value};
+use crate::fuzzer::FuzzCall;
+use crate::Edge;
+
+pub struct CorpusEntry {
+    pub sequence: Vec<FuzzCall>,
+    // edges first reached by the sequence
+    pub new_edges: usize,
+    // times the sequence was picked for mutation
+    pub picked: usize,
+}
+
+impl CorpusEntry {
+    // sequences that found more edges are mutated more, and less the more often they were already
+    pub fn energy(&self) -> f64 {
+        (1 + self.new_edges) as f64 / (1 + self.picked) as f64
+    }
+}
+
+#[derive(Default)]
+pub struct Corpus {
+    pub entries: Vec<CorpusEntry>,
+    // edges reached by all the entries
+    pub coverage: HashSet<Edge>,
+    // directory the entries are saved to, one JSON file per sequence
+    dir: Option<PathBuf>,
+}
+
+impl Corpus {
+    pub fn new(dir: Option<&str>) -> Self {
+        let dir = dir.map(PathBuf::from);
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir).unwrap_or_else(|e| panic!("cannot create corpus directory {:?}: {}", dir, e));
+        }
+        Corpus { entries: vec![], coverage: HashSet::new(), dir }
+    }
+
+    // calls of the sequences saved in the corpus directory, in file name order
+    pub fn saved_sequences(&self) -> Vec<Vec<Value>> {
+        let Some(dir) = &self.dir else { return vec![] };
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        paths.sort();
+        paths.iter().map(|path| {
+            let json: Value = serde_json::from_str(&fs::read_to_string(path).unwrap())
+                .unwrap_or_else(|e| panic!("invalid corpus entry {:?}: {}", path, e));
+            json["calls"].as_array().cloned().unwrap_or_default()
+        }).collect()
+    }
+
+    // keep the sequence if it reached edges that are not covered yet, and save it unless it was loaded from disk
+    pub fn add(&mut self, sequence: &[FuzzCall], edges: HashSet<Edge>, save: bool) -> bool {
+        let new_edges = edges.iter().filter(|edge| !self.coverage.contains(edge)).count();
+        if new_edges == 0 {
+            return false;
+        }
+        self.coverage.extend(edges);
+        if let (Some(dir), true) = (&self.dir, save) {
+            let json = json!({"calls": sequence.iter().map(FuzzCall::to_json).collect::<Vec<_>>()}).to_string();
+            let path = dir.join(format!("{}.json", &hex::encode(keccak256(json.as_bytes()))[..16]));
+            fs::write(&path, json).unwrap_or_else(|e| panic!("cannot write corpus entry {:?}: {}", path, e));
+        }
+        self.entries.push(CorpusEntry { sequence: sequence.to_vec(), new_edges, picked: 0 });
+        true
+    }
+
+    // index of an entry to mutate, chosen with probability proportional to its energy
+    pub fn pick(&mut self, rng: &mut StdRng) -> usize {
+        let weights = WeightedIndex::new(self.entries.iter().map(CorpusEntry::energy)).unwrap();
+        let index = weights.sample(rng);
+        self.entries[index].picked += 1;
+        index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use revm::primitives::B256;
+
+    #[test]
+    fn test_add_keeps_new_coverage_only() {
+        let mut corpus = Corpus::new(None);
+        let edge = |pc: usize| (B256::zero(), pc, pc + 1);
+        assert!(corpus.add(&[], HashSet::from([edge(1), edge(2)]), true));
+        assert!(!corpus.add(&[], HashSet::from([edge(2)]), true));
+        assert!(corpus.add(&[], HashSet::from([edge(2), edge(3)]), true));
+        assert_eq!(corpus.entries.len(), 2);
+        assert_eq!(corpus.entries[1].new_edges, 1);
+        assert_eq!(corpus.coverage.len(), 3);
+
+        // the first entry found more edges, so it is picked more
+        let mut rng = StdRng::seed_from_u64(0);
+        let picks: Vec<usize> = (0..100).map(|_| corpus.pick(&mut rng)).collect();
+        assert!(picks.iter().filter(|index| **index == 0).count() > 50);
+        assert_eq!(corpus.entries[0].picked + corpus.entries[1].picked, 100);
+    }
+}
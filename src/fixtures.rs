@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use serde_json::{json, Value};
+
+// recorded responses of a forked run
+// {
+//     "rpc": [{"method": "eth_getCode", "params": ["0x..", "latest"], "result": "0x.."}, ...],
+//     "http": [{"url": "/v5.0/1/swap?..", "response": {..}}, ...]
+// }
+// http urls are stored without scheme and host, so that they can be served by any stand-in server
+#[derive(Default, Clone)]
+pub struct Fixtures {
+    // JSON-RPC results keyed by rpc_key
+    pub rpc: HashMap<String, (String, Value, Value)>,
+    // HTTP responses keyed by path and query
+    pub http: HashMap<String, Value>,
+}
+
+pub fn rpc_key(method: &str, params: &Value) -> String {
+    // serde_json sorts object keys, so the key does not depend on how the params were built
+    format!("{} {}", method, params)
+}
+
+// path and query of an url, without the explorer API key so that it is not recorded
+pub fn http_key(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = match without_scheme.find('/') {
+        Some(index) => &without_scheme[index..],
+        None => "/",
+    };
+    match path.split_once('?') {
+        Some((path, query)) => {
+            let query: Vec<&str> = query.split('&').filter(|param| !param.starts_with("apikey=")).collect();
+            format!("{}?{}", path, query.join("&"))
+        }
+        None => path.to_string(),
+    }
+}
+
+impl Fixtures {
+    pub fn load(path: &str) -> Self {
+        let mut contents = String::new();
+        File::open(path).unwrap_or_else(|e| panic!("cannot open fixtures {}: {}", path, e))
+            .read_to_string(&mut contents).unwrap();
+        let json: Value = serde_json::from_str(&contents).unwrap();
+        let mut fixtures = Fixtures::default();
+        for entry in json["rpc"].as_array().into_iter().flatten() {
+            fixtures.insert_rpc(entry["method"].as_str().unwrap(), entry["params"].clone(), entry["result"].clone());
+        }
+        for entry in json["http"].as_array().into_iter().flatten() {
+            fixtures.insert_http(entry["url"].as_str().unwrap(), entry["response"].clone());
+        }
+        fixtures
+    }
+
+    // entries are sorted, so that re-recording the same run gives the same file
+    pub fn save(&self, path: &str) {
+        let mut rpc: Vec<_> = self.rpc.iter().collect();
+        rpc.sort_by(|a, b| a.0.cmp(b.0));
+        let mut http: Vec<_> = self.http.iter().collect();
+        http.sort_by(|a, b| a.0.cmp(b.0));
+        let json = json!({
+            "rpc": rpc.into_iter().map(|(_, (method, params, result))| json!({
+                "method": method,
+                "params": params,
+                "result": result,
+            })).collect::<Vec<_>>(),
+            "http": http.into_iter().map(|(url, response)| json!({
+                "url": url,
+                "response": response,
+            })).collect::<Vec<_>>(),
+        });
+        let mut file = File::create(path).unwrap_or_else(|e| panic!("cannot create fixtures {}: {}", path, e));
+        writeln!(file, "{}", serde_json::to_string_pretty(&json).unwrap()).unwrap();
+    }
+
+    pub fn extend(&mut self, other: Fixtures) {
+        self.rpc.extend(other.rpc);
+        self.http.extend(other.http);
+    }
+
+    pub fn insert_rpc(&mut self, method: &str, params: Value, result: Value) {
+        self.rpc.insert(rpc_key(method, &params), (method.to_string(), params, result));
+    }
+
+    pub fn insert_http(&mut self, url: &str, response: Value) {
+        self.http.insert(http_key(url), response);
+    }
+
+    pub fn get_rpc(&self, method: &str, params: &Value) -> Option<&Value> {
+        self.rpc.get(&rpc_key(method, params)).map(|(_, _, result)| result)
+    }
+
+    pub fn get_http(&self, url: &str) -> Option<&Value> {
+        self.http.get(&http_key(url))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let mut fixtures = Fixtures::default();
+        fixtures.insert_rpc("eth_getBalance", json!(["0x000000000000000000000000000000000000ca11", "latest"]), json!("0x1"));
+        fixtures.insert_rpc("eth_getCode", json!(["0x000000000000000000000000000000000000ca11", "latest"]), json!("0x"));
+        fixtures.insert_http("https://api.1inch.exchange/v5.0/1/quote?amount=1", json!({"protocols": []}));
+
+        let path = std::env::temp_dir().join(format!("fixtures-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fixtures.save(path);
+        let loaded = Fixtures::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.get_rpc("eth_getBalance", &json!(["0x000000000000000000000000000000000000ca11", "latest"])), Some(&json!("0x1")));
+        assert_eq!(loaded.get_rpc("eth_getCode", &json!(["0x000000000000000000000000000000000000ca11", "latest"])), Some(&json!("0x")));
+        assert_eq!(loaded.get_rpc("eth_getCode", &json!(["0x000000000000000000000000000000000000f00d", "latest"])), None);
+        // served by whatever host replays it
+        assert_eq!(loaded.get_http("http://127.0.0.1:8545/v5.0/1/quote?amount=1"), Some(&json!({"protocols": []})));
+        // explorer API keys are not recorded
+        assert_eq!(http_key("https://api.etherscan.io/api?module=contract&apikey=secret&address=0x1"), "/api?module=contract&address=0x1");
+    }
+}
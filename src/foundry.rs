@@ -0,0 +1,171 @@
+// Foundry tests reproducing the calls after which an invariant fired or a test failed, for sharing findings
+use std::fs;
+use std::path::Path;
+use bytes::Bytes;
+use revm::primitives::{keccak256, B160, U256};
+use crate::{rpc, TestHost};
+
+// state changes of the testkit that a Foundry test can reproduce, in the order they were made
+#[derive(Clone, Debug)]
+pub enum Step {
+    // set_balance and funded fuzzer senders
+    Deal { account: B160, amount: U256 },
+    SetNonce { account: B160, nonce: u64 },
+    // test_call, calls of the swaps of buy_token / sell_token_to_eth_best_path and calls a test makes itself
+    Call {
+        origin: B160,
+        caller: B160,
+        target: B160,
+        data: Bytes,
+        value: U256,
+        success: bool,
+        // Context function the call was made by
+        label: Option<String>,
+    },
+}
+
+pub struct Reproduction {
+    // name of the test contract, e.g. <spec>_<invariant>
+    pub name: String,
+    pub chain: String,
+    // None for the latest block
+    pub fork_block: Option<u64>,
+    // number and timestamp of the block the calls ran in, if it is not the forked one
+    pub block: Option<(U256, U256)>,
+    pub steps: Vec<Step>,
+    // print_* output of the invariant or test
+    pub output: Vec<String>,
+}
+
+// EIP-55, which solc requires for address literals
+pub fn checksum(address: B160) -> String {
+    let lower = hex::encode(address.0);
+    let hash = keccak256(lower.as_bytes());
+    let checksummed: String = lower.chars().enumerate().map(|(i, c)| {
+        let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0xf;
+        if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+    }).collect();
+    format!("0x{}", checksummed)
+}
+
+fn identifier(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+impl Reproduction {
+    // the steps recorded by the host since the last transaction or test started
+    pub fn new(host: &TestHost, spec: &str, function: &str, output: &[String]) -> Self {
+        let fork_block = rpc::fork_block().as_str().and_then(|block| u64::from_str_radix(block.trim_start_matches("0x"), 16).ok());
+        let block = (host.env.block.number > U256::ZERO).then_some((host.env.block.number, host.env.block.timestamp));
+        Reproduction {
+            name: identifier(&format!("{}_{}", spec, function)),
+            chain: host.chain.clone(),
+            fork_block,
+            block,
+            steps: host.steps.clone(),
+            output: output.to_vec(),
+        }
+    }
+
+    pub fn to_solidity(&self) -> String {
+        let mut lines = vec![
+            "// SPDX-License-Identifier: UNLICENSED".to_string(),
+            "pragma solidity ^0.8.0;".to_string(),
+            String::new(),
+            "import \"forge-std/Test.sol\";".to_string(),
+            String::new(),
+        ];
+        lines.extend(self.output.iter().map(|line| format!("// {}", line)));
+        lines.push(format!("contract {} is Test {{", self.name));
+        lines.push("    function setUp() public {".to_string());
+        let rpc_url = format!("vm.envString(\"{}_RPC_URL\")", self.chain.to_uppercase());
+        match self.fork_block {
+            Some(block) => lines.push(format!("        vm.createSelectFork({}, {});", rpc_url, block)),
+            None => lines.push(format!("        vm.createSelectFork({});", rpc_url)),
+        }
+        lines.push("    }".to_string());
+        lines.push(String::new());
+        lines.push("    function test_reproduce() public {".to_string());
+        if let Some((number, timestamp)) = self.block {
+            lines.push(format!("        vm.roll({});", number));
+            lines.push(format!("        vm.warp({});", timestamp));
+        }
+        if self.steps.iter().any(|step| matches!(step, Step::Call { .. })) {
+            lines.push("        bool success;".to_string());
+        }
+        for step in &self.steps {
+            match step {
+                Step::Deal { account, amount } => lines.push(format!("        vm.deal({}, {});", checksum(*account), amount)),
+                Step::SetNonce { account, nonce } => lines.push(format!("        vm.setNonce({}, {});", checksum(*account), nonce)),
+                Step::Call { origin, caller, target, data, value, success, label } => {
+                    if let Some(label) = label {
+                        lines.push(format!("        // {}", label));
+                    }
+                    lines.push(format!("        vm.prank({}, {});", checksum(*caller), checksum(*origin)));
+                    let value = if *value > U256::ZERO { format!("{{value: {}}}", value) } else { String::new() };
+                    lines.push(format!("        (success, ) = address({}).call{}(hex\"{}\");", checksum(*target), value, hex::encode(data)));
+                    lines.push(format!("        {}(success);", if *success { "assertTrue" } else { "assertFalse" }));
+                }
+            }
+        }
+        lines.push("    }".to_string());
+        lines.push("}".to_string());
+        lines.join("\n") + "\n"
+    }
+
+    // write <dir>/<name>.t.sol, returns its path
+    pub fn save(&self, dir: &str) -> String {
+        fs::create_dir_all(dir).unwrap_or_else(|e| panic!("cannot create {}: {}", dir, e));
+        let path = Path::new(dir).join(format!("{}.t.sol", self.name));
+        fs::write(&path, self.to_solidity()).unwrap_or_else(|e| panic!("cannot write {:?}: {}", path, e));
+        path.to_str().unwrap().to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_checksum() {
+        // from EIP-55
+        let address = B160::from_str("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap();
+        assert_eq!(checksum(address), "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+        let address = B160::from_str("fb6916095ca1df60bb79ce92ce3ea74c37c5d359").unwrap();
+        assert_eq!(checksum(address), "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359");
+    }
+
+    #[test]
+    fn test_to_solidity() {
+        let (sender, router) = (B160::from_low_u64_be(0x1000), B160::from_low_u64_be(0x1001));
+        let reproduction = Reproduction {
+            name: identifier("infinite_mint_invariant_infinite_mint0"),
+            chain: "ETH".to_string(),
+            fork_block: Some(17000000),
+            block: Some((U256::from(17000001), U256::from(1681000000))),
+            steps: vec![
+                Step::Deal { account: sender, amount: U256::from(10).pow(U256::from(19)) },
+                Step::Call {
+                    origin: sender, caller: sender, target: router, data: Bytes::from(vec![0xd0, 0xe3, 0x0d, 0xb0]),
+                    value: U256::from(1000), success: true, label: Some("buy_token".to_string()),
+                },
+            ],
+            output: vec!["total supply changed".to_string()],
+        };
+        let solidity = reproduction.to_solidity();
+        assert!(solidity.contains("contract infinite_mint_invariant_infinite_mint0 is Test {"));
+        assert!(solidity.contains("        vm.createSelectFork(vm.envString(\"ETH_RPC_URL\"), 17000000);\n"));
+        assert!(solidity.contains("        vm.warp(1681000000);\n"));
+        assert!(solidity.contains("        vm.deal(0x0000000000000000000000000000000000001000, 10000000000000000000);\n"));
+        assert!(solidity.contains(concat!(
+            "        // buy_token\n",
+            "        vm.prank(0x0000000000000000000000000000000000001000, 0x0000000000000000000000000000000000001000);\n",
+            "        (success, ) = address(0x0000000000000000000000000000000000001001).call{value: 1000}(hex\"d0e30db0\");\n",
+            "        assertTrue(success);\n",
+        )));
+        assert!(solidity.starts_with("// SPDX-License-Identifier: UNLICENSED\n"));
+        assert!(solidity.contains("// total supply changed\ncontract"));
+    }
+}
@@ -0,0 +1,546 @@
+// transaction sequence fuzzer: random calls with ABI-aware arguments to the target contracts from a pool of senders,
+// executed like test_call, with the invariants of the specs evaluated after each transaction
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+use bytes::Bytes;
+use ethabi::{Function, ParamType, StateMutability, Token};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use revm::primitives::{Bytecode, B160, B256, U256};
+use serde_json::{json, Value};
+use crate::alert::Alerter;
+use crate::corpus::Corpus;
+use crate::foundry::Step;
+use crate::minimizer::minimize;
+use crate::{get_explorer_api, rpc, Edge, HistoricalTx, InvariantOutcome, TestHost};
+
+// ETH each sender starts with
+const SENDER_BALANCE: u64 = 100;
+// upper bound of the length of generated bytes, strings and arrays
+const MAX_DYNAMIC_LENGTH: usize = 64;
+const MAX_ARRAY_LENGTH: usize = 4;
+// probability of mutating a corpus entry instead of generating a new sequence
+const MUTATION_PROBABILITY: f64 = 0.8;
+// probability of drawing a numeric, address or fixed bytes argument from the dictionary
+const DICTIONARY_PROBABILITY: f64 = 0.25;
+const MAX_DICTIONARY_SIZE: usize = 4096;
+
+pub struct FuzzTarget {
+    pub address: B160,
+    pub abi: ethabi::Contract,
+}
+
+impl FuzzTarget {
+    // <address>:<abi file>, or <address> to fetch the ABI of a verified contract from the block explorer
+    pub fn parse(target: &str, chain: &str) -> Self {
+        let (address, abi) = match target.split_once(':') {
+            Some((address, path)) => {
+                let file = File::open(path).unwrap_or_else(|e| panic!("cannot open ABI {}: {}", path, e));
+                (address, ethabi::Contract::load(file).unwrap())
+            }
+            None => (target, get_explorer_abi(chain, target)),
+        };
+        FuzzTarget {
+            address: B160::from_str(address.trim_start_matches("0x")).expect("invalid target address"),
+            abi,
+        }
+    }
+
+    // functions that can change state
+    pub fn functions(&self) -> Vec<&Function> {
+        self.abi.functions()
+            .filter(|function| !matches!(function.state_mutability, StateMutability::View | StateMutability::Pure))
+            .collect()
+    }
+}
+
+fn get_explorer_abi(chain: &str, address: &str) -> ethabi::Contract {
+    let mut url = format!("{}?module=contract&action=getabi&address={}", get_explorer_api(chain), address);
+    if let Ok(key) = env::var("ETHERSCAN_API_KEY") {
+        url.push_str(&format!("&apikey={}", key));
+    }
+    let response = rpc::get_json(&url).unwrap_or_else(|error| panic!("{}", error));
+    if response["status"] != "1" {
+        panic!("cannot fetch the ABI of {} from the explorer: {}", address, response["result"]);
+    }
+    serde_json::from_str(response["result"].as_str().unwrap()).unwrap()
+}
+
+#[derive(Clone)]
+pub struct FuzzCall {
+    pub sender: B160,
+    pub target: B160,
+    pub function: String,
+    pub args: Vec<Token>,
+    pub data: Bytes,
+    pub value: U256,
+}
+
+impl fmt::Display for FuzzCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+        write!(f, "{:?} -> {:?}.{}({})", self.sender, self.target, self.function, args.join(", "))?;
+        if self.value > U256::ZERO {
+            write!(f, " value {}", self.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FuzzCall {
+    // as saved in the corpus, the arguments are decoded again from the data when it is loaded
+    pub fn to_json(&self) -> Value {
+        json!({
+            "sender": format!("{:?}", self.sender),
+            "target": format!("{:?}", self.target),
+            "data": format!("0x{}", hex::encode(&self.data)),
+            "value": format!("{:#x}", self.value),
+        })
+    }
+}
+
+// sequence of calls after which an invariant fired
+pub struct Finding {
+    // minimized sequence
+    pub sequence: Vec<FuzzCall>,
+    // length of the sequence as found
+    pub found_length: usize,
+    pub outcome: InvariantOutcome,
+}
+
+// values the targets compare with or load from storage, and constants in their code,
+// which random arguments would hardly ever hit
+#[derive(Default)]
+pub struct Dictionary {
+    pub values: Vec<U256>,
+    known: HashSet<U256>,
+    // hashes of the code whose constants were added
+    scanned: HashSet<B256>,
+}
+
+impl Dictionary {
+    pub fn insert(&mut self, value: U256) {
+        if self.values.len() < MAX_DICTIONARY_SIZE && self.known.insert(value) {
+            self.values.push(value);
+        }
+    }
+
+    // add the operands of the PUSH instructions of a code
+    pub fn scan(&mut self, code: &Bytecode) {
+        if !self.scanned.insert(code.hash()) {
+            return;
+        }
+        let bytes = code.original_bytes();
+        let mut pc = 0;
+        while pc < bytes.len() {
+            // PUSH1 to PUSH32
+            if let 0x60..=0x7f = bytes[pc] {
+                let length = (bytes[pc] - 0x5f) as usize;
+                let operand = &bytes[pc + 1..(pc + 1 + length).min(bytes.len())];
+                self.insert(U256::from_be_bytes::<32>(left_pad(operand)));
+                pc += length;
+            }
+            pc += 1;
+        }
+    }
+}
+
+fn left_pad(bytes: &[u8]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(bytes);
+    word
+}
+
+pub struct Fuzzer {
+    rng: StdRng,
+    targets: Vec<FuzzTarget>,
+    pub senders: Vec<B160>,
+    pub sequence_length: usize,
+    pub corpus: Corpus,
+    pub dictionary: Dictionary,
+}
+
+fn uint_max(bits: usize) -> ethabi::Uint {
+    if bits >= 256 { ethabi::Uint::MAX } else { (ethabi::Uint::one() << bits) - 1 }
+}
+
+impl Fuzzer {
+    // funds `senders` new accounts on the host
+    pub fn new(targets: Vec<FuzzTarget>, host: &mut TestHost, senders: usize, sequence_length: usize, seed: u64) -> Self {
+        for target in &targets {
+            assert!(!target.functions().is_empty(), "target {:?} has no state changing functions", target.address);
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let senders = (0..senders).map(|_| {
+            let mut address = [0u8; 20];
+            rng.fill_bytes(&mut address);
+            let sender = B160::from(address);
+            fund(host, sender);
+            sender
+        }).collect();
+        Fuzzer { rng, targets, senders, sequence_length, corpus: Corpus::new(None), dictionary: Dictionary::default() }
+    }
+
+    // load the sequences saved in a corpus directory and replay them to restore its coverage,
+    // calls to unknown targets or functions are dropped and their senders are funded
+    pub fn load_corpus(&mut self, host: &mut TestHost, dir: &str) {
+        self.corpus = Corpus::new(Some(dir));
+        let sequences: Vec<Vec<FuzzCall>> = self.corpus.saved_sequences().iter()
+            .map(|calls| calls.iter().filter_map(|call| self.call_from_json(call)).collect())
+            .collect();
+        for call in sequences.iter().flatten() {
+            if !self.senders.contains(&call.sender) {
+                self.senders.push(call.sender);
+                fund(host, call.sender);
+            }
+        }
+        let initial = host.snapshot();
+        for sequence in sequences {
+            host.restore(initial.clone());
+            let mut edges = HashSet::new();
+            for call in &sequence {
+                edges.extend(self.execute(host, call));
+            }
+            self.corpus.add(&sequence, edges, false);
+        }
+        host.restore(initial);
+    }
+
+    fn call_from_json(&self, call: &Value) -> Option<FuzzCall> {
+        let address = |key: &str| B160::from_str(call[key].as_str()?.trim_start_matches("0x")).ok();
+        let (sender, target) = (address("sender")?, address("target")?);
+        let data = hex::decode(call["data"].as_str()?.trim_start_matches("0x")).ok()?;
+        let value = U256::from_str(call["value"].as_str()?).ok()?;
+        let function = self.function(target, &data)?;
+        let args = function.decode_input(&data[4..]).ok()?;
+        Some(FuzzCall { sender, target, function: function.name.clone(), args, data: Bytes::from(data), value })
+    }
+
+    // state changing function of a target called by `data`
+    pub fn function(&self, target: B160, data: &[u8]) -> Option<&Function> {
+        let target = self.targets.iter().find(|t| t.address == target)?;
+        target.functions().into_iter().find(|function| data.len() >= 4 && function.short_signature() == data[..4])
+    }
+
+    // execute a call, returns the edges it covered and adds the values it compared and the constants of the code it ran to the dictionary
+    pub fn execute(&mut self, host: &mut TestHost, call: &FuzzCall) -> HashSet<Edge> {
+        host.coverage = Some(HashSet::new());
+        host.execute_transaction(call.sender, call.target, call.data.clone(), call.value);
+        let edges = host.coverage.take().unwrap();
+        // the operands that are arguments of the call itself are nothing new
+        let arguments: HashSet<U256> = call.data.get(4..).unwrap_or_default().chunks(32)
+            .map(|word| U256::from_be_bytes::<32>(left_pad(word)))
+            .collect();
+        for value in std::mem::take(&mut host.cmp_log) {
+            if !arguments.contains(&value) {
+                self.dictionary.insert(value);
+            }
+        }
+        let hashes: HashSet<B256> = edges.iter().map(|(hash, _, _)| *hash).collect();
+        for (address, code) in &host.codes {
+            if hashes.contains(&code.hash()) || self.targets.iter().any(|target| target.address == *address) {
+                self.dictionary.scan(code);
+            }
+        }
+        edges
+    }
+
+    // execute a call without feeding back into fuzzing, e.g. to replay sequences while minimizing,
+    // coverage is only collected so that reverts are not printed
+    pub fn replay(&self, host: &mut TestHost, call: &FuzzCall) {
+        host.coverage = Some(HashSet::new());
+        host.execute_transaction(call.sender, call.target, call.data.clone(), call.value);
+        host.coverage = None;
+        host.cmp_log.clear();
+    }
+
+    // sometimes a random value of the dictionary
+    fn dictionary_value(&mut self) -> Option<U256> {
+        if self.dictionary.values.is_empty() || !self.rng.gen_bool(DICTIONARY_PROBABILITY) {
+            return None;
+        }
+        Some(self.dictionary.values[self.rng.gen_range(0..self.dictionary.values.len())])
+    }
+
+    // senders, targets and the zero address are the interesting ones
+    fn address(&mut self) -> B160 {
+        if let Some(value) = self.dictionary_value().filter(|value| value.bit_len() <= 160) {
+            return B160::from_slice(&value.to_be_bytes::<32>()[12..]);
+        }
+        match self.rng.gen_range(0..8) {
+            0 => B160::zero(),
+            1 => {
+                let mut address = [0u8; 20];
+                self.rng.fill_bytes(&mut address);
+                B160::from(address)
+            }
+            2 | 3 => self.targets[self.rng.gen_range(0..self.targets.len())].address,
+            _ => self.senders[self.rng.gen_range(0..self.senders.len())],
+        }
+    }
+
+    fn uint(&mut self, bits: usize) -> ethabi::Uint {
+        if let Some(value) = self.dictionary_value() {
+            return ethabi::Uint::from_big_endian(&value.to_be_bytes::<32>()) & uint_max(bits);
+        }
+        match self.rng.gen_range(0..8) {
+            0 => ethabi::Uint::zero(),
+            1 => ethabi::Uint::one(),
+            2 => uint_max(bits),
+            // token amounts with 18 decimals
+            3 => (ethabi::Uint::from(self.rng.gen_range(1..=100u64)) * ethabi::Uint::exp10(18)).min(uint_max(bits)),
+            4 => {
+                let mut bytes = [0u8; 32];
+                self.rng.fill_bytes(&mut bytes);
+                ethabi::Uint::from_big_endian(&bytes) & uint_max(bits)
+            }
+            _ => ethabi::Uint::from(self.rng.gen_range(0..1000u64)) & uint_max(bits),
+        }
+    }
+
+    // two's complement over 256 bits, as ABI encoded
+    fn int(&mut self, bits: usize) -> ethabi::Uint {
+        // values that are in range as a signed integer of `bits` bits
+        let value = self.dictionary_value().map(|value| ethabi::Uint::from_big_endian(&value.to_be_bytes::<32>()));
+        if let Some(value) = value.filter(|value| *value <= uint_max(bits - 1) || *value >= ethabi::Uint::MAX << (bits - 1)) {
+            return value;
+        }
+        match self.rng.gen_range(0..6) {
+            0 => ethabi::Uint::zero(),
+            // minimum and maximum
+            1 => ethabi::Uint::MAX << (bits - 1),
+            2 => uint_max(bits - 1),
+            _ => {
+                let value = self.rng.gen_range(-1000i64..1000);
+                if value < 0 {
+                    ethabi::Uint::MAX - ethabi::Uint::from(value.unsigned_abs() - 1)
+                } else {
+                    ethabi::Uint::from(value)
+                }
+            }
+        }
+    }
+
+    fn bytes(&mut self, length: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; length];
+        self.rng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    pub fn token(&mut self, kind: &ParamType) -> Token {
+        match kind {
+            ParamType::Address => Token::Address(ethabi::Address::from_slice(&self.address().0)),
+            ParamType::Bytes => {
+                let length = self.rng.gen_range(0..=MAX_DYNAMIC_LENGTH);
+                Token::Bytes(self.bytes(length))
+            }
+            ParamType::Int(bits) => Token::Int(self.int(*bits)),
+            ParamType::Uint(bits) => Token::Uint(self.uint(*bits)),
+            ParamType::Bool => Token::Bool(self.rng.gen()),
+            ParamType::String => {
+                let length = self.rng.gen_range(0..=MAX_DYNAMIC_LENGTH);
+                Token::String((0..length).map(|_| self.rng.gen_range(b'a'..=b'z') as char).collect())
+            }
+            ParamType::Array(inner) => {
+                let length = self.rng.gen_range(0..=MAX_ARRAY_LENGTH);
+                Token::Array((0..length).map(|_| self.token(inner)).collect())
+            }
+            ParamType::FixedBytes(length) => match self.dictionary_value() {
+                // right aligned when it fits, e.g. a PUSH4 of a selector, left aligned like bytesN otherwise
+                Some(value) => {
+                    let word = value.to_be_bytes::<32>();
+                    Token::FixedBytes(if value.bit_len() <= length * 8 { word[32 - length..].to_vec() } else { word[..*length].to_vec() })
+                }
+                None => Token::FixedBytes(self.bytes(*length)),
+            },
+            ParamType::FixedArray(inner, length) => Token::FixedArray((0..*length).map(|_| self.token(inner)).collect()),
+            ParamType::Tuple(kinds) => Token::Tuple(kinds.iter().map(|kind| self.token(kind)).collect()),
+        }
+    }
+
+    // random call of a state changing function of a random target
+    pub fn call(&mut self) -> FuzzCall {
+        let target = &self.targets[self.rng.gen_range(0..self.targets.len())];
+        let functions = target.functions();
+        let function = functions[self.rng.gen_range(0..functions.len())].clone();
+        let target = target.address;
+
+        let args: Vec<Token> = function.inputs.iter().map(|input| self.token(&input.kind)).collect();
+        let value = if function.state_mutability == StateMutability::Payable && self.rng.gen_bool(0.5) {
+            U256::from(self.rng.gen_range(1..=10u64)) * U256::from(10).pow(U256::from(17))
+        } else {
+            U256::ZERO
+        };
+        FuzzCall {
+            sender: self.senders[self.rng.gen_range(0..self.senders.len())],
+            target,
+            data: Bytes::from(function.encode_input(&args).unwrap()),
+            function: function.name,
+            args,
+            value,
+        }
+    }
+
+    // mutated copy of a sequence of the corpus
+    fn mutate(&mut self, index: usize) -> Vec<FuzzCall> {
+        let mut sequence = self.corpus.entries[index].sequence.clone();
+        let position = self.rng.gen_range(0..sequence.len());
+        match self.rng.gen_range(0..6) {
+            // new arguments for one of the calls
+            0 => {
+                let call = &sequence[position];
+                let function = self.function(call.target, &call.data).unwrap().clone();
+                let argument = self.rng.gen_range(0..=function.inputs.len());
+                let call = &mut sequence[position];
+                if argument < function.inputs.len() {
+                    call.args[argument] = self.token(&function.inputs[argument].kind);
+                } else {
+                    call.args = function.inputs.iter().map(|input| self.token(&input.kind)).collect();
+                }
+                call.data = Bytes::from(function.encode_input(&call.args).unwrap());
+            }
+            1 => sequence[position] = self.call(),
+            2 if sequence.len() < self.sequence_length => sequence.insert(position, self.call()),
+            3 if sequence.len() > 1 => { sequence.remove(position); }
+            4 => sequence[position].sender = self.senders[self.rng.gen_range(0..self.senders.len())],
+            // splice with the tail of another sequence
+            _ => {
+                let other = &self.corpus.entries[self.rng.gen_range(0..self.corpus.entries.len())].sequence;
+                let tail = other[self.rng.gen_range(0..other.len())..].to_vec();
+                sequence.truncate(position + 1);
+                sequence.extend(tail);
+                sequence.truncate(self.sequence_length);
+            }
+        }
+        sequence
+    }
+
+    // next sequence to run, mutated from the corpus or random
+    fn sequence(&mut self) -> Vec<FuzzCall> {
+        if !self.corpus.is_empty() && self.rng.gen_bool(MUTATION_PROBABILITY) {
+            let index = self.corpus.pick(&mut self.rng);
+            self.mutate(index)
+        } else {
+            (0..self.sequence_length).map(|_| self.call()).collect()
+        }
+    }
+
+    // run a sequence on the current state of the host, until one of its calls makes an invariant fire
+    // that is not in `fired`, returns the calls executed, the new outcomes that fired and the edges covered
+    pub fn run_sequence(&mut self, host: &mut TestHost, specs: &[B160], fired: &HashSet<(B160, String)>, sequence: Vec<FuzzCall>) -> (Vec<FuzzCall>, Vec<InvariantOutcome>, HashSet<Edge>) {
+        let mut executed = vec![];
+        let mut edges = HashSet::new();
+        for call in sequence {
+            edges.extend(self.execute(host, &call));
+            executed.push(call);
+            let new: Vec<InvariantOutcome> = host.evaluate_invariants(specs).into_iter()
+                .filter(|outcome| outcome.fired && !fired.contains(&(outcome.spec, outcome.name.clone())))
+                .collect();
+            if !new.is_empty() {
+                return (executed, new, edges);
+            }
+        }
+        (executed, vec![], edges)
+    }
+
+    // run `iterations` sequences, each from the initial state of the host, keeping the ones that reach new edges in the corpus,
+    // returns the first sequence found for each invariant that fired, minimized
+    pub fn fuzz(&mut self, host: &mut TestHost, specs: &[B160], iterations: usize, alerter: &Alerter) -> Vec<Finding> {
+        let initial = host.snapshot();
+        let mut fired = HashSet::new();
+        let mut findings = vec![];
+        for _ in 0..iterations {
+            host.restore(initial.clone());
+            let sequence = self.sequence();
+            let (sequence, outcomes, edges) = self.run_sequence(host, specs, &fired, sequence);
+            self.corpus.add(&sequence, edges, true);
+            if outcomes.is_empty() {
+                continue;
+            }
+            for outcome in outcomes {
+                fired.insert((outcome.spec, outcome.name.clone()));
+                let (minimized, outcome) = minimize(self, host, &initial, &sequence, &outcome);
+                let last = minimized.last().unwrap();
+                let tx = HistoricalTx {
+                    hash: B256::zero(),
+                    block: 0,
+                    caller: last.sender,
+                    target: Some(last.target),
+                    data: last.data.clone(),
+                    value: last.value,
+                    gas_limit: host.gas_limit,
+                    gas_price: U256::ZERO,
+                };
+                alerter.invariant_fired(host, &tx, &outcome, "fuzz");
+                findings.push(Finding { sequence: minimized, found_length: sequence.len(), outcome });
+            }
+        }
+        host.restore(initial);
+        findings
+    }
+}
+
+fn fund(host: &mut TestHost, sender: B160) {
+    let amount = U256::from(SENDER_BALANCE) * U256::from(10).pow(U256::from(18));
+    host.set_balance.insert(sender, amount);
+    host.nonces.insert(sender, 0);
+    host.codes.insert(sender, Bytecode::new());
+    host.steps.push(Step::Deal { account: sender, amount });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_arguments_are_valid() {
+        let abi: ethabi::Contract = serde_json::from_str(r#"[{
+            "type": "function", "name": "f", "stateMutability": "payable", "outputs": [],
+            "inputs": [
+                {"name": "a", "type": "address"}, {"name": "b", "type": "uint8"}, {"name": "c", "type": "int16"},
+                {"name": "d", "type": "bytes"}, {"name": "e", "type": "string"}, {"name": "f", "type": "bytes4"},
+                {"name": "g", "type": "uint256[]"}, {"name": "h", "type": "bool[2]"},
+                {"name": "i", "type": "tuple", "components": [{"name": "x", "type": "int256"}, {"name": "y", "type": "address"}]}
+            ]
+        }, {"type": "function", "name": "g", "stateMutability": "view", "inputs": [], "outputs": []}]"#).unwrap();
+        let target = FuzzTarget { address: B160::from_low_u64_be(0x1000), abi };
+        assert_eq!(target.functions().len(), 1);
+        let mut fuzzer = Fuzzer {
+            rng: StdRng::seed_from_u64(0),
+            targets: vec![target],
+            senders: vec![B160::from_low_u64_be(0x2000)],
+            sequence_length: 1,
+            corpus: Corpus::new(None),
+            dictionary: Dictionary::default(),
+        };
+        // values of any size drawn from the dictionary still fit the types
+        fuzzer.dictionary.insert(U256::MAX);
+        fuzzer.dictionary.insert(U256::from(0xdeadbeefu64) << 200);
+        let function = fuzzer.targets[0].abi.function("f").unwrap().clone();
+        let kinds: Vec<ParamType> = function.inputs.iter().map(|input| input.kind.clone()).collect();
+        for _ in 0..200 {
+            let call = fuzzer.call();
+            assert_eq!(call.function, "f");
+            // what Solidity would accept, i.e. values fit their types
+            assert_eq!(ethabi::decode(&kinds, &call.data[4..]).unwrap(), call.args);
+            let Token::Uint(b) = call.args[1] else { panic!() };
+            assert!(b <= ethabi::Uint::from(u8::MAX));
+            let Token::Int(c) = call.args[2] else { panic!() };
+            assert!(c <= ethabi::Uint::from(i16::MAX) || c >= ethabi::Uint::MAX << 15);
+        }
+    }
+
+    #[test]
+    fn test_dictionary_scans_push_operands() {
+        let mut dictionary = Dictionary::default();
+        // PUSH4 0x12345678 PUSH1 0x01 ADD PUSH2 0x0203 (truncated)
+        let code = Bytecode::new_raw(Bytes::from(vec![0x63, 0x12, 0x34, 0x56, 0x78, 0x60, 0x01, 0x01, 0x61, 0x02]));
+        dictionary.scan(&code);
+        dictionary.scan(&code);
+        assert_eq!(dictionary.values, vec![U256::from(0x12345678), U256::from(1), U256::from(2)]);
+    }
+}
@@ -1,10 +1,26 @@
 use std::str::FromStr;
+use std::sync::RwLock;
 use bytes::Bytes;
 use ethabi::Uint;
 use revm::primitives::{B160, U256};
+use crate::rpc;
 
 
-pub static INCH_API: &str = "https://api.1inch.exchange/v5.0/";
+pub static DEFAULT_INCH_API: &str = "https://api.1inch.exchange/v5.0/";
+static INCH_API: RwLock<String> = RwLock::new(String::new());
+
+pub fn set_inch_api(url: String) {
+    *INCH_API.write().unwrap() = url;
+}
+
+fn inch_api() -> String {
+    let url = INCH_API.read().unwrap();
+    if url.is_empty() {
+        DEFAULT_INCH_API.to_string()
+    } else {
+        url.clone()
+    }
+}
 
 pub fn get_router_and_weth(network: &str) -> (B160, B160, u8) {
     match network {
@@ -26,21 +42,20 @@ pub fn get_router_and_weth(network: &str) -> (B160, B160, u8) {
 
 fn get_1inch_swap_args(from_token: B160, to_token: B160, amount: U256,caller: B160, network: &str) -> (U256, B160, Bytes) {
     let (_,_,chain_id) = get_router_and_weth(network);
-    let result = reqwest::blocking::get(
+    let json = rpc::get_json(
         format!(
             "{}{}/swap?fromTokenAddress={:?}&toTokenAddress={:?}&amount={}&disableEstimate=true&slippage=50&fromAddress={:?}",
-            INCH_API,
+            inch_api(),
             chain_id,
             from_token,
             to_token,
-            amount.to_string(),
+            amount,
             caller
-        )
-    ).unwrap();
-    let json: serde_json::Value = result.json().unwrap();
+        ).as_str()
+    ).unwrap_or_else(|error| panic!("{}", error));
     // println!("{:?}", format!(
     //     "{}{}/swap?fromTokenAddress={:?}&toTokenAddress={:?}&amount={}&disableEstimate=true&slippage=50&fromAddress={:?}",
-    //     INCH_API,
+    //     inch_api(),
     //     chain_id,
     //     from_token,
     //     to_token,
@@ -58,42 +73,34 @@ fn get_1inch_swap_args(from_token: B160, to_token: B160, amount: U256,caller: B1
         data_by
     );
     let value = U256::from_str(tx.get("value").unwrap().as_str().unwrap()).unwrap();
-    return (value, to, data);
+    (value, to, data)
 }
 
 
+#[allow(dead_code)]
 fn find_best_path_1nch(from_token: B160, to_token: B160, amount: U256, liquidation_src: String) -> Vec<B160> {
-    let result = reqwest::blocking::get(
-        format!(
-            "{}1/quote?fromTokenAddress={:?}&toTokenAddress={:?}&amount={}&protocols={}",
-            INCH_API,
-            from_token,
-            to_token,
-            amount.to_string(),
-            liquidation_src
-        )
-    ).unwrap();
-    println!("{:?}", format!(
+    let url = format!(
         "{}1/quote?fromTokenAddress={:?}&toTokenAddress={:?}&amount={}&protocols={}",
-        INCH_API,
+        inch_api(),
         from_token,
         to_token,
-        amount.to_string(),
+        amount,
         liquidation_src
-    ));
-    let json: serde_json::Value = result.json().unwrap();
+    );
+    println!("{:?}", url);
+    let json = rpc::get_json(url.as_str()).unwrap_or_else(|error| panic!("{}", error));
 
 
     let protocols = json["protocols"].as_array().unwrap();
     // find shortest path
     let mut path = vec![];
     let mut current_min_len = 100000;
-    assert!(protocols.len() > 0, "Cannot find swap path for {:?} -> {:?}", from_token, to_token);
+    assert!(!protocols.is_empty(), "Cannot find swap path for {:?} -> {:?}", from_token, to_token);
     for protocol in protocols {
         let protocol_arr = protocol.as_array().unwrap();
-        let proper = protocol_arr.iter().map(|x| {
+        let proper = protocol_arr.iter().all(|x| {
             x.as_array().unwrap().len() == 1
-        }).all(|x| x);
+        });
         if !proper {
             continue;
         }
@@ -103,7 +110,7 @@ fn find_best_path_1nch(from_token: B160, to_token: B160, amount: U256, liquidati
         }
     }
 
-    assert!(path.len() > 0, "Cannot find proper swap path for {:?} -> {:?}", from_token, to_token);
+    assert!(!path.is_empty(), "Cannot find proper swap path for {:?} -> {:?}", from_token, to_token);
 
     macro_rules! get_token {
         ($i: expr, $k: expr) => {
@@ -115,11 +122,9 @@ fn find_best_path_1nch(from_token: B160, to_token: B160, amount: U256, liquidati
         get_token!(0, "fromTokenAddress"),
     ];
 
-    for i in 0..path.len() {
-        token_path.push(get_token!(i, "toTokenAddress"));
-    }
+    token_path.extend((0..path.len()).map(|i| get_token!(i, "toTokenAddress")));
 
-    return token_path;
+    token_path
 }
 
 
@@ -134,13 +139,13 @@ pub fn buy_token(
         // directly deposit
         return (amount, weth, Bytes::from(vec![0xd0, 0xe3, 0x0d, 0xb0]));
     }
-    return get_1inch_swap_args(
+    get_1inch_swap_args(
         B160::from_str("0xeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee").unwrap(),
         token,
         amount,
         caller,
         network
-    );
+    )
     // let path = find_best_path_1nch(
     //     weth,
     //     token,
@@ -214,12 +219,12 @@ pub fn sell_token(
             ]
         ).to_vec();
 
-    let approve_tx = vec![approve_hash, approve_args].concat();
+    let approve_tx = [approve_hash, approve_args].concat();
 
-    return vec![
+    vec![
         (U256::ZERO, token, Bytes::from(approve_tx)),
         (U256::ZERO, target, bys),
-    ];
+    ]
 }
 
 
@@ -228,9 +233,11 @@ pub fn sell_token(
 mod tests {
     use std::str::FromStr;
     use super::*;
+    use crate::mock_server;
 
     #[test]
     fn test_find_best_path_1inch() {
+        mock_server::shared();
         let path = find_best_path_1nch(
             B160::from_str("0xf3ae5d769e153ef72b4e3591ac004e89f48107a1").unwrap(),
             B160::from_str("0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE").unwrap(),
@@ -238,10 +245,16 @@ mod tests {
             "UNISWAP_V2".to_string()
         );
         println!("{:?}", path);
+        assert_eq!(path, vec![
+            B160::from_str("0xf3ae5d769e153ef72b4e3591ac004e89f48107a1").unwrap(),
+            B160::from_str("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2").unwrap(),
+            B160::from_str("0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE").unwrap(),
+        ]);
     }
 
     #[test]
     fn test_swap_1inch() {
+        mock_server::shared();
         let (value, target, bys) = get_1inch_swap_args(
             B160::from_str("0xf3ae5d769e153ef72b4e3591ac004e89f48107a1").unwrap(),
             B160::from_str("0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE").unwrap(),
@@ -249,18 +262,25 @@ mod tests {
             B160::from_str("0xe8a7dB54F27FC7B855AE9BC950341878952EfF98").unwrap(),
             "ETH"
         );
-        println!("{:?}", (value, target, hex::encode(bys)));
+        println!("{:?}", (value, target, hex::encode(bys.clone())));
+        assert_eq!(value, U256::ZERO);
+        assert_eq!(target, B160::from_str("0x1111111254eeb25477b68fb85ed929f73a960582").unwrap());
+        assert_eq!(&bys[0..4], &[0x12, 0xaa, 0x3c, 0xaf]);
     }
 
 
     #[test]
     fn test_buy_token() {
+        mock_server::shared();
         let (amount, router, data) = buy_token(
             B160::from_str("0xf3ae5d769e153ef72b4e3591ac004e89f48107a1").unwrap(),
             U256::from_str("1257979238016341134939").unwrap(),
             B160::from_str("0xe8a7dB54F27FC7B855AE9BC950341878952EfF98").unwrap(),
             "ETH"
         );
-        println!("{:?}", (amount, router, data));
+        println!("{:?}", (amount, router, data.clone()));
+        assert_eq!(amount, U256::from_str("1257979238016341134939").unwrap());
+        assert_eq!(router, B160::from_str("0x1111111254eeb25477b68fb85ed929f73a960582").unwrap());
+        assert_eq!(&data[0..4], &[0x12, 0xaa, 0x3c, 0xaf]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+// delta debugging of the call sequences that make an invariant fire: drop calls, shrink arguments and ETH values
+// and use fewer senders, as long as the same invariant still fires
+use ethabi::Token;
+use revm::primitives::{B160, U256};
+use bytes::Bytes;
+use crate::fuzzer::{FuzzCall, Fuzzer};
+use crate::{InvariantOutcome, StateSnapshot, TestHost};
+
+// upper bound of the passes over a sequence, each pass only runs if the previous one shrank it
+const MAX_PASSES: usize = 8;
+
+struct Minimizer<'a> {
+    fuzzer: &'a Fuzzer,
+    host: &'a mut TestHost,
+    initial: &'a StateSnapshot,
+    spec: B160,
+    invariant: String,
+}
+
+impl Minimizer<'_> {
+    // replay a sequence from the initial state, returns the outcome and the number of calls after which the invariant fired
+    fn fires(&mut self, sequence: &[FuzzCall]) -> Option<(usize, InvariantOutcome)> {
+        self.host.restore(self.initial.clone());
+        for (index, call) in sequence.iter().enumerate() {
+            self.fuzzer.replay(self.host, call);
+            let outcome = self.host.evaluate_invariants(&[self.spec]).into_iter()
+                .find(|outcome| outcome.fired && outcome.name == self.invariant);
+            if let Some(outcome) = outcome {
+                return Some((index + 1, outcome));
+            }
+        }
+        None
+    }
+
+    // the candidate cut to the calls up to the one that makes the invariant fire, if it does
+    fn try_sequence(&mut self, mut candidate: Vec<FuzzCall>) -> Option<Vec<FuzzCall>> {
+        let (length, _) = self.fires(&candidate)?;
+        candidate.truncate(length);
+        Some(candidate)
+    }
+
+    // ddmin over the calls: remove chunks of the sequence, refining the chunks when none can be removed
+    fn drop_calls(&mut self, mut sequence: Vec<FuzzCall>) -> Vec<FuzzCall> {
+        let mut chunks = 2;
+        while sequence.len() > 1 {
+            let chunk_size = sequence.len().div_ceil(chunks);
+            let mut reduced = false;
+            for start in (0..sequence.len()).step_by(chunk_size) {
+                let mut candidate = sequence.clone();
+                candidate.drain(start..(start + chunk_size).min(sequence.len()));
+                if let Some(candidate) = self.try_sequence(candidate) {
+                    sequence = candidate;
+                    chunks = (chunks - 1).max(2);
+                    reduced = true;
+                    break;
+                }
+            }
+            if !reduced {
+                if chunk_size == 1 {
+                    break;
+                }
+                chunks = (chunks * 2).min(sequence.len());
+            }
+        }
+        sequence
+    }
+
+    // smallest value for which the sequence still reproduces, by bisection between zero and `value`
+    fn shrink_value(&mut self, value: ethabi::Uint, mut with: impl FnMut(ethabi::Uint) -> Vec<FuzzCall>) -> Option<Vec<FuzzCall>> {
+        if value.is_zero() {
+            return None;
+        }
+        if let Some(sequence) = self.try_sequence(with(ethabi::Uint::zero())) {
+            return Some(sequence);
+        }
+        let (mut low, mut high) = (ethabi::Uint::zero(), value);
+        let mut best = None;
+        while high - low > ethabi::Uint::one() {
+            let middle = low + (high - low) / 2;
+            match self.try_sequence(with(middle)) {
+                Some(sequence) => {
+                    high = middle;
+                    best = Some(sequence);
+                }
+                None => low = middle,
+            }
+        }
+        best
+    }
+
+    // simpler values of an argument, tried in order
+    fn simplify(token: &Token) -> Vec<Token> {
+        match token {
+            Token::Bool(true) => vec![Token::Bool(false)],
+            Token::Bytes(bytes) if !bytes.is_empty() => vec![Token::Bytes(vec![])],
+            Token::String(string) if !string.is_empty() => vec![Token::String(String::new())],
+            Token::Array(items) if !items.is_empty() => vec![Token::Array(vec![]), Token::Array(items[..items.len() / 2].to_vec())],
+            _ => vec![],
+        }
+    }
+
+    fn shrink_arguments(&mut self, mut sequence: Vec<FuzzCall>) -> Vec<FuzzCall> {
+        let mut index = 0;
+        while index < sequence.len() {
+            let function = self.fuzzer.function(sequence[index].target, &sequence[index].data).unwrap().clone();
+            for argument in 0..sequence[index].args.len() {
+                let with = |sequence: &Vec<FuzzCall>, token: Token| {
+                    let mut candidate = sequence.clone();
+                    let call = &mut candidate[index];
+                    call.args[argument] = token;
+                    call.data = Bytes::from(function.encode_input(&call.args).unwrap());
+                    candidate
+                };
+                match sequence[index].args[argument].clone() {
+                    Token::Uint(value) => {
+                        if let Some(shrunk) = self.shrink_value(value, |value| with(&sequence, Token::Uint(value))) {
+                            sequence = shrunk;
+                        }
+                    }
+                    // negative values are shrunk to zero, or else towards -1
+                    Token::Int(value) if value.bit(255) => {
+                        if let Some(shrunk) = self.try_sequence(with(&sequence, Token::Int(ethabi::Uint::zero()))) {
+                            sequence = shrunk;
+                        } else if let Some(shrunk) = self.shrink_value(!value, |magnitude| with(&sequence, Token::Int(!magnitude))) {
+                            sequence = shrunk;
+                        }
+                    }
+                    Token::Int(value) => {
+                        if let Some(shrunk) = self.shrink_value(value, |value| with(&sequence, Token::Int(value))) {
+                            sequence = shrunk;
+                        }
+                    }
+                    token => {
+                        for simpler in Self::simplify(&token) {
+                            if let Some(shrunk) = self.try_sequence(with(&sequence, simpler)) {
+                                sequence = shrunk;
+                                break;
+                            }
+                        }
+                    }
+                }
+                // a shrunk argument may make the invariant fire earlier
+                if index >= sequence.len() {
+                    return sequence;
+                }
+            }
+            index += 1;
+        }
+        sequence
+    }
+
+    fn shrink_values(&mut self, mut sequence: Vec<FuzzCall>) -> Vec<FuzzCall> {
+        let mut index = 0;
+        while index < sequence.len() {
+            let value = ethabi::Uint::from_big_endian(&sequence[index].value.to_be_bytes::<32>());
+            let with = |sequence: &Vec<FuzzCall>, value: ethabi::Uint| {
+                let mut candidate = sequence.clone();
+                let mut bytes = [0u8; 32];
+                value.to_big_endian(&mut bytes);
+                candidate[index].value = U256::from_be_bytes(bytes);
+                candidate
+            };
+            if let Some(shrunk) = self.shrink_value(value, |value| with(&sequence, value)) {
+                sequence = shrunk;
+            }
+            index += 1;
+        }
+        sequence
+    }
+
+    // send the calls from the senders that are used first
+    fn simplify_senders(&mut self, mut sequence: Vec<FuzzCall>) -> Vec<FuzzCall> {
+        let senders = self.fuzzer.senders.clone();
+        let mut index = 0;
+        while index < sequence.len() {
+            for sender in senders.iter().take_while(|sender| **sender != sequence[index].sender) {
+                let mut candidate = sequence.clone();
+                candidate[index].sender = *sender;
+                if let Some(shrunk) = self.try_sequence(candidate) {
+                    sequence = shrunk;
+                    break;
+                }
+            }
+            index += 1;
+        }
+        sequence
+    }
+}
+
+// minimal sequence that still makes the invariant of `outcome` fire from the initial state,
+// the host is left in the state after it with its outcome
+pub fn minimize(fuzzer: &Fuzzer, host: &mut TestHost, initial: &StateSnapshot, sequence: &[FuzzCall], outcome: &InvariantOutcome) -> (Vec<FuzzCall>, InvariantOutcome) {
+    let mut minimizer = Minimizer { fuzzer, host, initial, spec: outcome.spec, invariant: outcome.name.clone() };
+    let original = sequence;
+    let mut sequence = sequence.to_vec();
+    for _ in 0..MAX_PASSES {
+        let previous: Vec<String> = sequence.iter().map(|call| call.to_string()).collect();
+        sequence = minimizer.drop_calls(sequence);
+        sequence = minimizer.shrink_values(sequence);
+        sequence = minimizer.shrink_arguments(sequence);
+        sequence = minimizer.simplify_senders(sequence);
+        if sequence.iter().map(|call| call.to_string()).eq(previous) {
+            break;
+        }
+    }
+    match minimizer.fires(&sequence) {
+        Some((_, outcome)) => (sequence, outcome),
+        // replays are not deterministic if they depend on state fetched in the meantime, the original sequence
+        // is kept, the host is left after it as it is for the minimized one
+        None => {
+            println!("minimized sequence of {} does not reproduce, keeping the original one", minimizer.invariant);
+            minimizer.fires(original);
+            (original.to_vec(), outcome.clone())
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// local stand-in for the JSON-RPC node and the 1inch API, serving recorded fixtures
+// so that the host can be tested without network access
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use glob::glob;
+use serde_json::{json, Value};
+use crate::fixtures::Fixtures;
+use crate::liquidation::set_inch_api;
+use crate::rpc::set_endpoints;
+
+// start serving on a free local port, returns the base url
+pub fn start(fixtures: Fixtures) -> String {
+    start_with_errors(fixtures, vec![])
+}
+
+// like `start`, but the first requests are answered with the given HTTP status and body in order,
+// e.g. to rate limit the client before the fixtures are served
+pub fn start_with_errors(fixtures: Fixtures, errors: Vec<(&'static str, Value)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let fixtures = Arc::new(fixtures);
+    let errors = Arc::new(Mutex::new(VecDeque::from(errors)));
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (fixtures, errors) = (fixtures.clone(), errors.clone());
+            std::thread::spawn(move || handle(stream, &fixtures, &errors));
+        }
+    });
+    url
+}
+
+// server with every fixture in ./fixtures, shared by all tests, the RPC endpoints and the 1inch API point to it
+pub fn shared() -> &'static str {
+    static URL: OnceLock<String> = OnceLock::new();
+    URL.get_or_init(|| {
+        let mut fixtures = Fixtures::default();
+        for path in glob(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/*.json")).unwrap().flatten() {
+            fixtures.extend(Fixtures::load(path.to_str().unwrap()));
+        }
+        let url = start(fixtures);
+        set_endpoints(vec![url.clone()]);
+        set_inch_api(format!("{}/v5.0/", url));
+        url
+    })
+}
+
+fn rpc_response(fixtures: &Fixtures, request: &Value) -> Value {
+    let method = request["method"].as_str().unwrap_or_default();
+    match fixtures.get_rpc(method, &request["params"]) {
+        Some(result) => json!({"jsonrpc": "2.0", "id": request["id"], "result": result}),
+        None => json!({"jsonrpc": "2.0", "id": request["id"], "error": {
+            "code": -32000,
+            "message": format!("no fixture for {} {}", method, request["params"]),
+        }}),
+    }
+}
+
+fn handle(stream: TcpStream, fixtures: &Fixtures, errors: &Mutex<VecDeque<(&'static str, Value)>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let scripted = errors.lock().unwrap().pop_front();
+    let (status, response) = if let Some(error) = scripted {
+        error
+    } else if method == "POST" {
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Array(requests)) => ("200 OK", Value::Array(
+                requests.iter().map(|request| rpc_response(fixtures, request)).collect()
+            )),
+            Ok(request) => ("200 OK", rpc_response(fixtures, &request)),
+            Err(_) => ("400 Bad Request", json!({"error": "invalid JSON-RPC request"})),
+        }
+    } else {
+        match fixtures.get_http(target) {
+            Some(response) => ("200 OK", response.clone()),
+            None => ("404 Not Found", json!({"error": format!("no fixture for {}", target)})),
+        }
+    };
+
+    let response = response.to_string();
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, response.len(), response
+    );
+}
@@ -0,0 +1,404 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::thread::sleep;
+use std::time::Duration;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use crate::fixtures::{rpc_key, Fixtures};
+
+// requests per JSON-RPC batch, public endpoints reject larger batches
+pub const BATCH_SIZE: usize = 100;
+
+// JSON-RPC error codes of nodes that do not implement a method
+const METHOD_NOT_FOUND: i64 = -32601;
+const METHOD_NOT_SUPPORTED: i64 = -32004;
+// JSON-RPC error codes used for rate limiting (EIP-1474 limit exceeded, HTTP status mirrored by some providers)
+const LIMIT_EXCEEDED: i64 = -32005;
+const TOO_MANY_REQUESTS: i64 = 429;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_ATTEMPTS: usize = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+// endpoints of the forked chain, requests fail over to the next one
+static ENDPOINTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static CURRENT_ENDPOINT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_endpoints(urls: Vec<String>) {
+    *ENDPOINTS.write().unwrap() = urls;
+    CURRENT_ENDPOINT.store(0, Ordering::Relaxed);
+}
+
+// block the forked state is read at, the latest block unless forking a past one
+static FORK_BLOCK: RwLock<Option<u64>> = RwLock::new(None);
+
+pub fn set_fork_block(number: Option<u64>) {
+    *FORK_BLOCK.write().unwrap() = number;
+}
+
+// block parameter of state queries
+pub fn fork_block() -> Value {
+    match *FORK_BLOCK.read().unwrap() {
+        Some(number) => json!(format!("{:#x}", number)),
+        None => json!("latest"),
+    }
+}
+
+// results of state queries, which do not change for a given block, so that state dropped locally
+// (e.g. when the fuzzer resets to the initial state) is not fetched again
+const CACHED_METHODS: [&str; 4] = ["eth_getCode", "eth_getBalance", "eth_getTransactionCount", "eth_getStorageAt"];
+static CACHE: OnceLock<Mutex<HashMap<String, Value>>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<HashMap<String, Value>> {
+    CACHE.get_or_init(Default::default)
+}
+
+fn cached(method: &str, params: &Value) -> Option<Value> {
+    if !CACHED_METHODS.contains(&method) {
+        return None;
+    }
+    cache().lock().unwrap().get(&rpc_key(method, params)).cloned()
+}
+
+fn cache_result(method: &str, params: &Value, result: &Result<Value, RpcError>) {
+    if let (true, Ok(result)) = (CACHED_METHODS.contains(&method), result) {
+        cache().lock().unwrap().insert(rpc_key(method, params), result.clone());
+    }
+}
+
+// every successful response is added to the recording (--record)
+static RECORDING: Mutex<Option<Fixtures>> = Mutex::new(None);
+// responses are served from these fixtures only (--replay)
+static REPLAY: RwLock<Option<Fixtures>> = RwLock::new(None);
+
+pub fn start_recording() {
+    *RECORDING.lock().unwrap() = Some(Fixtures::default());
+}
+
+pub fn take_recording() -> Option<Fixtures> {
+    RECORDING.lock().unwrap().take()
+}
+
+pub fn replay(fixtures: Fixtures) {
+    *REPLAY.write().unwrap() = Some(fixtures);
+}
+
+fn record_rpc(method: &str, params: &Value, result: &Result<Value, RpcError>) {
+    if let (Some(recording), Ok(result)) = (RECORDING.lock().unwrap().as_mut(), result) {
+        recording.insert_rpc(method, params.clone(), result.clone());
+    }
+}
+
+fn replayed_rpc(fixtures: &Fixtures, method: &str, params: &Value) -> Result<Value, RpcError> {
+    match fixtures.get_rpc(method, params) {
+        Some(result) => Ok(result.clone()),
+        None => Err(RpcError::Other(format!("replay: no fixture for {} {}", method, params))),
+    }
+}
+
+// client shared by all requests, keeps connections to the node alive between requests
+pub fn client() -> &'static reqwest::blocking::Client {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::blocking::Client::builder()
+            .pool_max_idle_per_host(16)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap()
+    })
+}
+
+pub enum RpcError {
+    // the node does not implement the method
+    Unsupported,
+    Other(String),
+}
+
+fn is_rate_limit_error(error: &Value) -> bool {
+    let message = error["message"].as_str().unwrap_or_default().to_lowercase();
+    matches!(error["code"].as_i64(), Some(LIMIT_EXCEEDED) | Some(TOO_MANY_REQUESTS))
+        || message.contains("rate limit")
+        || message.contains("too many requests")
+}
+
+// whether a response (or any response of a batch) asks us to slow down
+fn is_rate_limited(response: &Value) -> bool {
+    match response {
+        Value::Array(responses) => responses.iter().any(is_rate_limited),
+        response => response.get("error").is_some_and(is_rate_limit_error),
+    }
+}
+
+fn parse_response(response: reqwest::blocking::Response) -> Result<Value, String> {
+    let status = response.status();
+    if status.as_u16() == 429 || status.is_server_error() {
+        return Err(format!("HTTP {}", status));
+    }
+    let body = response.text().map_err(|e| e.to_string())?;
+    let response = serde_json::from_str::<Value>(&body)
+        .map_err(|_| format!("invalid response {:?}", body))?;
+    if is_rate_limited(&response) {
+        return Err("rate limited".to_string());
+    }
+    Ok(response)
+}
+
+// run a request until it succeeds, `wait`ing with exponential backoff between attempts
+fn with_retries(mut attempt: impl FnMut() -> Result<Value, String>, mut wait: impl FnMut(Duration)) -> Result<Value, String> {
+    let mut backoff = INITIAL_BACKOFF;
+    for n in 1..=MAX_ATTEMPTS {
+        match attempt() {
+            Ok(response) => return Ok(response),
+            Err(error) if n == MAX_ATTEMPTS => {
+                return Err(format!("request failed after {} attempts: {}", MAX_ATTEMPTS, error));
+            }
+            Err(error) => {
+                println!("{}, retrying in {:?}", error, backoff);
+                wait(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+    unreachable!()
+}
+
+// send a JSON-RPC request body to the configured endpoints
+fn send(body: &Value) -> Result<Value, String> {
+    let endpoints = ENDPOINTS.read().unwrap().clone();
+    send_to(&endpoints, &CURRENT_ENDPOINT, body, sleep)
+}
+
+// send a JSON-RPC request body, failing over from the `current` endpoint to the next one
+// on network errors, timeouts and rate limiting
+fn send_to(endpoints: &[String], current: &AtomicUsize, body: &Value, wait: impl FnMut(Duration)) -> Result<Value, String> {
+    if endpoints.is_empty() {
+        return Err("no RPC endpoint configured".to_string());
+    }
+    with_retries(|| {
+        let index = current.load(Ordering::Relaxed) % endpoints.len();
+        client().post(&endpoints[index])
+            .json(body)
+            .send()
+            .map_err(|e| e.to_string())
+            .and_then(parse_response)
+            .map_err(|error| {
+                // other threads may already have failed over
+                let _ = current.compare_exchange(index, index + 1, Ordering::Relaxed, Ordering::Relaxed);
+                format!("RPC request to {} failed ({})", endpoints[index], error)
+            })
+    }, wait)
+}
+
+// GET a JSON document (e.g. from the 1inch API) with the same retry policy
+pub fn get_json(url: &str) -> Result<Value, String> {
+    if let Some(fixtures) = REPLAY.read().unwrap().as_ref() {
+        return fixtures.get_http(url).cloned().ok_or_else(|| format!("replay: no fixture for GET {}", url));
+    }
+    let response = with_retries(|| {
+        client().get(url)
+            .send()
+            .map_err(|e| e.to_string())
+            .and_then(parse_response)
+            .map_err(|error| format!("GET {} failed ({})", url, error))
+    }, sleep)?;
+    if let Some(recording) = RECORDING.lock().unwrap().as_mut() {
+        recording.insert_http(url, response.clone());
+    }
+    Ok(response)
+}
+
+fn into_result(response: &Value) -> Result<Value, RpcError> {
+    if let Some(error) = response.get("error") {
+        return match error["code"].as_i64() {
+            Some(METHOD_NOT_FOUND) | Some(METHOD_NOT_SUPPORTED) => Err(RpcError::Unsupported),
+            _ => Err(RpcError::Other(error.to_string())),
+        };
+    }
+    match response.get("result") {
+        Some(result) => Ok(result.clone()),
+        None => Err(RpcError::Other(format!("invalid response {}", response))),
+    }
+}
+
+pub fn try_request(method: &str, params: Value) -> Result<Value, RpcError> {
+    if let Some(fixtures) = REPLAY.read().unwrap().as_ref() {
+        return replayed_rpc(fixtures, method, &params);
+    }
+    if let Some(result) = cached(method, &params) {
+        return Ok(result);
+    }
+    let result = send(&json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1
+    })).map_err(RpcError::Other).and_then(|response| into_result(&response));
+    record_rpc(method, &params, &result);
+    cache_result(method, &params, &result);
+    result
+}
+
+// result of a request, errors are described for the caller to report
+pub fn request(method: &str, params: Value) -> Result<Value, String> {
+    try_request(method, params).map_err(|error| match error {
+        RpcError::Unsupported => format!("{} is not supported by the node", method),
+        RpcError::Other(error) => format!("{} failed: {}", method, error),
+    })
+}
+
+// send requests as JSON-RPC batches of BATCH_SIZE, the batches are sent in parallel,
+// results are in the order of the requests
+pub fn batch_request(requests: &[(&str, Value)]) -> Vec<Result<Value, RpcError>> {
+    if let Some(fixtures) = REPLAY.read().unwrap().as_ref() {
+        return requests.iter().map(|(method, params)| replayed_rpc(fixtures, method, params)).collect();
+    }
+    let mut results: Vec<Option<Result<Value, RpcError>>> = requests.iter()
+        .map(|(method, params)| cached(method, params).map(Ok))
+        .collect();
+    let missing: Vec<usize> = (0..requests.len()).filter(|i| results[*i].is_none()).collect();
+    let missing_requests: Vec<(&str, Value)> = missing.iter().map(|i| requests[*i].clone()).collect();
+    for (i, result) in missing.into_iter().zip(send_batches(&missing_requests)) {
+        results[i] = Some(result);
+    }
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+fn send_batches(requests: &[(&str, Value)]) -> Vec<Result<Value, RpcError>> {
+    let batches: Vec<Value> = requests.chunks(BATCH_SIZE).enumerate().map(|(batch, chunk)| {
+        Value::Array(chunk.iter().enumerate().map(|(i, (method, params))| json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": batch * BATCH_SIZE + i
+        })).collect())
+    }).collect();
+
+    let mut results: Vec<Result<Value, RpcError>> = (0..requests.len())
+        .map(|_| Err(RpcError::Other("missing response".to_string())))
+        .collect();
+    let responses: Vec<Value> = std::thread::scope(|scope| {
+        let handles: Vec<_> = batches.iter().map(|batch| scope.spawn(move || send(batch))).collect();
+        handles.into_iter().enumerate().flat_map(|(batch, handle)| match handle.join().unwrap() {
+            Ok(Value::Array(responses)) => responses,
+            // some nodes answer a batch they reject with a single error object
+            Ok(response) => vec![response],
+            Err(error) => {
+                let end = ((batch + 1) * BATCH_SIZE).min(requests.len());
+                for result in &mut results[batch * BATCH_SIZE..end] {
+                    *result = Err(RpcError::Other(error.clone()));
+                }
+                vec![]
+            }
+        }).collect()
+    });
+
+    for response in responses {
+        if let Some(id) = response["id"].as_u64() {
+            if let Some(result) = results.get_mut(id as usize) {
+                *result = into_result(&response);
+            }
+        }
+    }
+    for ((method, params), result) in requests.iter().zip(&results) {
+        record_rpc(method, params, result);
+        cache_result(method, params, result);
+    }
+    results
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_detection() {
+        assert!(is_rate_limited(&json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32005, "message": "limit exceeded"}})));
+        assert!(is_rate_limited(&json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "Rate limit reached"}})));
+        assert!(is_rate_limited(&json!([
+            {"jsonrpc": "2.0", "id": 0, "result": "0x0"},
+            {"jsonrpc": "2.0", "id": 1, "error": {"code": 429, "message": "Too Many Requests"}},
+        ])));
+        assert!(!is_rate_limited(&json!({"jsonrpc": "2.0", "id": 1, "error": {"code": 3, "message": "execution reverted"}})));
+        assert!(!is_rate_limited(&json!({"jsonrpc": "2.0", "id": 1, "result": "0x0"})));
+    }
+
+    #[test]
+    fn test_error_responses() {
+        assert!(matches!(into_result(&json!({"id": 1, "error": {"code": -32601, "message": "the method does not exist"}})),
+                         Err(RpcError::Unsupported)));
+        assert!(matches!(into_result(&json!({"id": 1, "error": {"code": -32000, "message": "header not found"}})),
+                         Err(RpcError::Other(_))));
+        assert!(matches!(into_result(&json!({"id": 1})), Err(RpcError::Other(_))));
+        assert!(matches!(into_result(&json!({"id": 1, "result": null})), Ok(Value::Null)));
+    }
+
+    fn chain_id_request() -> Value {
+        json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": [], "id": 1})
+    }
+
+    // node answering eth_chainId once the scripted errors are used up
+    fn chain_id_server(errors: Vec<(&'static str, Value)>) -> String {
+        let mut fixtures = Fixtures::default();
+        fixtures.insert_rpc("eth_chainId", json!([]), json!("0x1"));
+        crate::mock_server::start_with_errors(fixtures, errors)
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let mut waits = vec![];
+        let mut attempts = 0;
+        let result = with_retries(|| {
+            attempts += 1;
+            Err("connection refused".to_string())
+        }, |backoff| waits.push(backoff));
+        assert_eq!(result.unwrap_err(), "request failed after 8 attempts: connection refused");
+        assert_eq!(attempts, MAX_ATTEMPTS);
+        let millis: Vec<u128> = waits.iter().map(Duration::as_millis).collect();
+        assert_eq!(millis, vec![250, 500, 1000, 2000, 4000, 8000, 8000]);
+    }
+
+    #[test]
+    fn test_rate_limited_requests_are_retried() {
+        let url = chain_id_server(vec![
+            ("429 Too Many Requests", json!({})),
+            ("200 OK", json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32005, "message": "limit exceeded"}})),
+            ("503 Service Unavailable", json!({})),
+        ]);
+        let mut waits = vec![];
+        let response = send_to(&[url], &AtomicUsize::new(0), &chain_id_request(), |backoff| waits.push(backoff)).unwrap();
+        assert!(matches!(into_result(&response), Ok(result) if result == "0x1"));
+        assert_eq!(waits, vec![INITIAL_BACKOFF, INITIAL_BACKOFF * 2, INITIAL_BACKOFF * 4]);
+    }
+
+    #[test]
+    fn test_failover_to_next_endpoint() {
+        let url = chain_id_server(vec![]);
+        // nothing listens on port 1
+        let endpoints = ["http://127.0.0.1:1".to_string(), url];
+        let current = AtomicUsize::new(0);
+        let mut waits = vec![];
+        let response = send_to(&endpoints, &current, &chain_id_request(), |backoff| waits.push(backoff)).unwrap();
+        assert!(matches!(into_result(&response), Ok(result) if result == "0x1"));
+        assert_eq!(current.load(Ordering::Relaxed), 1);
+        assert_eq!(waits, vec![INITIAL_BACKOFF]);
+
+        // later requests go to the endpoint that answered
+        waits.clear();
+        send_to(&endpoints, &current, &chain_id_request(), |backoff| waits.push(backoff)).unwrap();
+        assert!(waits.is_empty());
+    }
+
+    #[test]
+    fn test_other_errors_are_returned_without_retrying() {
+        let url = chain_id_server(vec![]);
+        let body = json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": [], "id": 1});
+        let mut waits = vec![];
+        let response = send_to(&[url], &AtomicUsize::new(0), &body, |backoff| waits.push(backoff)).unwrap();
+        assert!(waits.is_empty());
+        assert!(matches!(into_result(&response), Err(RpcError::Other(error)) if error.contains("no fixture for eth_getBalance")));
+
+        assert_eq!(send_to(&[], &AtomicUsize::new(0), &body, |_| {}).unwrap_err(), "no RPC endpoint configured");
+    }
+}